@@ -1,6 +1,6 @@
 use macroquad::prelude::*;
 
-use quad_net::quad_socket::client::QuadSocket;
+use quad_net::quad_socket::client::{ConnectionState, QuadSocket};
 
 #[macroquad::main("Networking!")]
 async fn main() {
@@ -9,18 +9,15 @@ async fn main() {
     #[cfg(target_arch = "wasm32")]
     let mut socket = QuadSocket::connect("ws://localhost:8091").unwrap();
 
-    #[cfg(target_arch = "wasm32")]
-    {
-        while socket.is_wasm_websocket_connected() == false {
-            next_frame().await;
-        }
+    while let ConnectionState::Connecting = socket.state() {
+        next_frame().await;
     }
 
     let mut pos = vec2(0.0, 0.0);
     let mut last_edit_id = 0;
 
     loop {
-        while let Some((x, y, id)) = socket.try_recv_bin() {
+        while let Ok(Some((x, y, id))) = socket.try_recv_bin() {
             pos.x = x;
             pos.y = y;
             last_edit_id = id;
@@ -34,9 +31,16 @@ async fn main() {
         );
         draw_circle(pos.x, pos.y, 15., RED);
 
+        match socket.state() {
+            ConnectionState::Closed { .. } | ConnectionState::Failed(_) => {
+                draw_text("Connection lost", 20.0, 40.0, 30.0, WHITE);
+            }
+            _ => {}
+        }
+
         if is_mouse_button_down(MouseButton::Left) {
             let (x, y) = mouse_position();
-            socket.send_bin(&(x, y));
+            let _ = socket.send_bin(&(x, y));
         }
         next_frame().await
     }
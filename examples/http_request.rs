@@ -7,8 +7,9 @@ async fn main() {
     let mut request = RequestBuilder::new("http://127.0.0.1:4000/").send();
 
     loop {
-        if let Some(data) = request.try_recv() {
-            info!("Done! {}", data.unwrap());
+        if let Some(response) = request.try_recv() {
+            let response = response.unwrap();
+            info!("Done! {} {}", response.status, response.text().unwrap());
         }
         next_frame().await;
     }
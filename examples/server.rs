@@ -1,10 +1,9 @@
-use nanoserde::DeBin;
+use nanoserde::{DeBin, SerBin};
+use quad_net::quad_socket::server::SocketHandle;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
 
-#[derive(Default)]
 struct ClientState {
-    id: Option<usize>,
+    id: usize,
 }
 
 struct World {
@@ -24,26 +23,33 @@ pub fn main() -> std::io::Result<()> {
         "0.0.0.0:8090",
         "0.0.0.0:8091",
         quad_net::quad_socket::server::Settings {
-            on_message: {
+            on_connect: {
                 let world = world.clone();
-                move |mut _out, mut state: &mut ClientState, msg| {
-                    let msg: (f32, f32) = DeBin::deserialize_bin(&msg).unwrap();
+                move |_, _| {
+                    let mut world = world.lock().unwrap();
+                    let id = world.unique_id;
+                    world.unique_id += 1;
 
-                    if state.id.is_none() {
-                        state.id = Some(world.lock().unwrap().unique_id);
-                        world.lock().unwrap().unique_id += 1;
-                    }
-                    world.lock().unwrap().last_edit_id = state.id.unwrap();
-                    world.lock().unwrap().pos = msg;
+                    Some(ClientState { id })
                 }
             },
-            on_timer: move |out, _state| {
-                let world = world.lock().unwrap();
-                out.send_bin(&(world.pos.0, world.pos.1, world.last_edit_id))
-                    .unwrap();
+            on_message: move |out: &mut SocketHandle, state: &mut ClientState, msg| {
+                let msg: (f32, f32) = DeBin::deserialize_bin(&msg).unwrap();
+
+                let mut world = world.lock().unwrap();
+                world.last_edit_id = state.id;
+                world.pos = msg;
+
+                let data = (world.pos.0, world.pos.1, world.last_edit_id);
+                out.broadcast(&SerBin::serialize_bin(&data)).unwrap();
             },
+            on_timer: |_, _| {},
             on_disconnect: |_| {},
-            timer: Some(Duration::from_millis(100)),
+            on_suspend: |_, _| {},
+            on_resume: |_, _, _| {},
+            timer: None,
+            max_frame_size: 64 * 1024,
+            session_grace: Some(std::time::Duration::from_secs(30)),
             _marker: std::marker::PhantomData,
         },
     );
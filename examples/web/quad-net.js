@@ -4,100 +4,265 @@ function on_init() {
 register_plugin = function (importObject) {
     importObject.env.ws_connect = ws_connect;
     importObject.env.ws_is_connected = ws_is_connected;
+    importObject.env.ws_state = ws_state;
+    importObject.env.ws_close_reason = ws_close_reason;
+    importObject.env.ws_poll_event = ws_poll_event;
     importObject.env.ws_send = ws_send;
-    importObject.env.ws_try_recv = ws_try_recv;
+    importObject.env.ws_close = ws_close;
+    importObject.env.ws_forget = ws_forget;
+    importObject.env.ws_protocol = ws_protocol;
 
     importObject.env.http_make_request = http_make_request;
     importObject.env.http_try_recv = http_try_recv;
+    importObject.env.http_progress_received = http_progress_received;
+    importObject.env.http_progress_total = http_progress_total;
+    importObject.env.http_cancel = http_cancel;
+    importObject.env.http_forget = http_forget;
+
+    importObject.env.quad_net_now = quad_net_now;
+    importObject.env.quad_net_random = quad_net_random;
+}
+
+miniquad_add_plugin({ register_plugin, on_init, version: 11, name: "quad_net" });
+
+function quad_net_now() {
+    return Date.now();
 }
 
-miniquad_add_plugin({ register_plugin, on_init, version: "0.1.0", name: "quad_net" });
+function quad_net_random() {
+    return Math.random();
+}
+
+var ws_uid = 0;
+// every open socket, by the id handed to rust
+var sockets = {};
 
-var socket;
-var connected = 0;
-var received_buffer = [];
+// state: 0 - connecting, 1 - connected, 2 - closed, 3 - failed
+function ws_is_connected(id) {
+    return sockets[id] != undefined && sockets[id].state == 1 ? 1 : 0;
+}
 
-function ws_is_connected() {
-    return connected;
+function ws_state(id) {
+    return sockets[id] != undefined ? sockets[id].state : 2;
 }
 
-function ws_connect(addr) {
-    socket = new WebSocket(consume_js_object(addr));
+function ws_close_reason(id) {
+    return js_object(sockets[id] != undefined ? sockets[id].close_reason : "");
+}
+
+// kind: 0 - connected, 1 - closed, 2 - failed, 3 - message
+function ws_poll_event(id) {
+    if (sockets[id] != undefined && sockets[id].events.length != 0) {
+        return js_object(sockets[id].events.shift());
+    }
+    return -1;
+}
+
+// protocols: comma separated, empty for none
+function ws_connect(addr, protocols) {
+    var id = ws_uid;
+
+    ws_uid += 1;
+
+    var addr_string = consume_js_object(addr);
+    var protocols_string = consume_js_object(protocols);
+    var protocols_array = protocols_string != "" ? protocols_string.split(",") : [];
+    var entry = {
+        "socket": new WebSocket(addr_string, protocols_array),
+        "state": 0,
+        "close_reason": "",
+        "events": []
+    };
+    var socket = entry.socket;
     socket.binaryType = 'arraybuffer';
     socket.onopen = function() {
-        connected = 1;
+        entry.state = 1;
+        entry.events.push({ "kind": 0 });
+    };
+
+    socket.onerror = function() {
+        entry.state = 3;
+        entry.events.push({ "kind": 2 });
+    };
+
+    socket.onclose = function(e) {
+        // an error is always followed by close, keep the error
+        if (entry.state == 3) {
+            return;
+        }
+        entry.state = 2;
+        entry.close_reason = e.reason != "" ? e.reason : "Closed with code " + e.code;
+        entry.events.push({ "kind": 1, "code": e.code, "reason": e.reason });
     };
 
+    // messages go through the events, to keep their order with the close
     socket.onmessage = function(msg) {
-        var buffer = new Uint8Array(msg.data);
-        received_buffer.push(buffer);
+        if (typeof msg.data == "string") {
+            entry.events.push({
+                "kind": 3,
+                "text": 1,
+                "data": msg.data
+            });
+        } else {
+            var buffer = new Uint8Array(msg.data);
+            entry.events.push({
+                "kind": 3,
+                "text": 0,
+                "data": buffer
+            });
+        }
+
     }
-};
+    sockets[id] = entry;
 
-function ws_send(data) {
-    var array = consume_js_object(data);
-    socket.send(array.buffer);
+    return id;
 };
 
-function ws_try_recv() {
-    if (received_buffer.length != 0) {
-        return js_object(received_buffer.shift())
+function ws_close(id, code, reason) {
+    var reason_string = consume_js_object(reason);
+    if (sockets[id] != undefined) {
+        sockets[id].socket.close(code, reason_string);
     }
-    return -1;
 }
 
+// the subprotocol picked by the server, empty before the connection is open
+function ws_protocol(id) {
+    return js_object(sockets[id] != undefined ? sockets[id].socket.protocol : "");
+}
+
+// the rust side is gone, nobody is going to poll the events
+function ws_forget(id) {
+    var entry = sockets[id];
+    delete sockets[id];
+    if (entry != undefined) {
+        entry.socket.onopen = null;
+        entry.socket.onerror = null;
+        entry.socket.onclose = null;
+        entry.socket.onmessage = null;
+        entry.socket.close();
+    }
+}
+
+function ws_send(id, data) {
+    var array = consume_js_object(data);
+    if (sockets[id] == undefined) {
+        return;
+    }
+    // here should be a nice typecheck on array.is_string or whatever
+    if (array.buffer != undefined) {
+        sockets[id].socket.send(array.buffer);
+    } else {
+        sockets[id].socket.send(array);
+    }
+};
+
 
 var uid = 0;
+// finished requests, waiting for http_try_recv
 var ongoing_requests = {};
+// requests in flight
+var ongoing_xhrs = {};
+// { received, total } of every request, until forgotten, total is -1 if unknown
+var http_progress = {};
 
 function http_try_recv(cid) {
-    if (ongoing_requests[cid] != undefined && ongoing_requests[cid] != null) {
+    if (ongoing_requests[cid] != undefined) {
         var data = ongoing_requests[cid];
-        ongoing_requests[cid] = null;
+        delete ongoing_requests[cid];
         return js_object(data);
     }
     return -1;
 }
 
-function http_make_request(scheme, url, body, headers) {
-    var cid = uid;
-
-    uid += 1;
+function http_finish(cid, result) {
+    // forgotten, nobody is going to ask for the result
+    if (ongoing_xhrs[cid] == undefined) {
+        return;
+    }
+    delete ongoing_xhrs[cid];
+    ongoing_requests[cid] = result;
+}
 
-    var scheme_string;
-    if (scheme == 0) {
-        scheme_string = 'POST';
+function http_progress_received(cid) {
+    if (http_progress[cid] != undefined) {
+        return http_progress[cid].received;
     }
-    if (scheme == 1) {
-        scheme_string = 'PUT';
+    return 0;
+}
+
+function http_progress_total(cid) {
+    if (http_progress[cid] != undefined) {
+        return http_progress[cid].total;
     }
-    if (scheme == 2) {
-        scheme_string = 'GET';
+    return -1;
+}
+
+function http_cancel(cid) {
+    if (ongoing_xhrs[cid] != undefined) {
+        ongoing_xhrs[cid].abort();
     }
-    if (scheme == 3) {
-        scheme_string = 'DELETE';
+}
+
+function http_forget(cid) {
+    var xhr = ongoing_xhrs[cid];
+    delete ongoing_xhrs[cid];
+    delete ongoing_requests[cid];
+    delete http_progress[cid];
+    if (xhr != undefined) {
+        xhr.abort();
     }
+}
+
+function http_make_request(method, url, body, headers, timeout_ms) {
+    var cid = uid;
+
+    uid += 1;
+
+    var method_string = consume_js_object(method);
     var url_string = consume_js_object(url);
-    var body_string = consume_js_object(body);
+    var body_array = consume_js_object(body);
     var headers_obj = consume_js_object(headers);
-    console.log(headers_obj);
     var xhr = new XMLHttpRequest();
-    xhr.open('GET', url_string, true);
+    xhr.open(method_string, url_string, true);
     xhr.responseType = 'arraybuffer';
+    xhr.timeout = timeout_ms;
+    for (const header in headers_obj) {
+        xhr.setRequestHeader(header, headers_obj[header]);
+    }
+    http_progress[cid] = { "received": 0, "total": -1 };
+    xhr.onprogress = function (e) {
+        if (http_progress[cid] != undefined) {
+            http_progress[cid] = {
+                "received": e.loaded,
+                "total": e.lengthComputable ? e.total : -1
+            };
+        }
+    };
+    // error: 0 - none, 1 - network error or CORS, 2 - timeout, 3 - aborted
     xhr.onload = function (e) {
-        if (this.status == 200) {
-            var uInt8Array = new Uint8Array(this.response);
-            
-            ongoing_requests[cid] = uInt8Array;
+        if (http_progress[cid] != undefined) {
+            var length = this.response.byteLength;
+            http_progress[cid] = { "received": length, "total": length };
         }
+        http_finish(cid, {
+            "error": 0,
+            "status": this.status,
+            "headers": this.getAllResponseHeaders(),
+            "body": new Uint8Array(this.response)
+        });
     }
     xhr.onerror = function (e) {
-        // todo: let rust know and put Error to ongoing requests
-        console.error("Failed to make a request");
-        console.error(e);
+        http_finish(cid, { "error": 1 });
+    };
+    xhr.ontimeout = function (e) {
+        http_finish(cid, { "error": 2 });
+    };
+    xhr.onabort = function (e) {
+        http_finish(cid, { "error": 3 });
     };
+    ongoing_xhrs[cid] = xhr;
 
-    xhr.send(body);
+    xhr.send(body_array.length != 0 ? body_array : null);
 
     return cid;
 }
@@ -0,0 +1,134 @@
+//! Exponential backoff with jitter.
+
+use std::time::Duration;
+
+#[cfg(target_arch = "wasm32")]
+extern "C" {
+    fn quad_net_now() -> f64;
+    fn quad_net_random() -> f64;
+}
+
+/// Current time in seconds. Only meaningful relative to other `now()` calls.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> f64 {
+    unsafe { quad_net_now() / 1000. }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn seed() -> u64 {
+    (unsafe { quad_net_random() } * u64::MAX as f64) as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn seed() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.as_nanos())
+            .unwrap_or(0),
+    );
+    hasher.finish()
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f32,
+    jitter: f32,
+    attempt: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    /// `jitter` is the fraction of each delay that gets randomized, from 0.0 to 1.0.
+    pub fn new(
+        initial_delay: Duration,
+        max_delay: Duration,
+        multiplier: f32,
+        jitter: f32,
+    ) -> Backoff {
+        Backoff {
+            initial_delay,
+            max_delay,
+            multiplier,
+            jitter: jitter.clamp(0., 1.),
+            attempt: 0,
+            // xorshift gets stuck on 0
+            rng: seed() | 1,
+        }
+    }
+
+    /// Amount of delays handed out since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.initial_delay.as_secs_f32() * self.multiplier.powi(self.attempt as i32);
+        let delay = delay.min(self.max_delay.as_secs_f32());
+        self.attempt += 1;
+
+        let random = self.next_random();
+        Duration::from_secs_f32(delay * (1. - self.jitter * random))
+    }
+
+    /// Uniformly distributed in 0.0..1.0
+    fn next_random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+
+        (self.rng >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(delays: &[f32]) -> Vec<Duration> {
+        delays
+            .iter()
+            .map(|&delay| Duration::from_secs_f32(delay))
+            .collect()
+    }
+
+    #[test]
+    fn grows_up_to_max_delay() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10), 2., 0.);
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        assert_eq!(delays, secs(&[1., 2., 4., 8., 10., 10.]));
+        assert_eq!(backoff.attempt(), 6);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60), 3., 0.);
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn jitter_only_shortens() {
+        let mut backoff = Backoff::new(Duration::from_secs(4), Duration::from_secs(4), 1., 0.5);
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+}
@@ -1,6 +1,43 @@
 #[derive(Debug)]
 pub enum Error {
     IOError(std::io::Error),
+    /// A frame exceeded the maximum allowed frame size.
+    FrameTooLarge {
+        size: usize,
+        max: usize,
+    },
+    /// The connection is closed or was never established.
+    ConnectionClosed,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IOError(error) => write!(f, "IO error: {}", error),
+            Error::FrameTooLarge { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the {} bytes limit", size, max)
+            }
+            Error::ConnectionClosed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Clone for Error {
+    fn clone(&self) -> Error {
+        match self {
+            // io::Error is not Clone, keep what matters: the kind and the message.
+            Error::IOError(error) => {
+                Error::IOError(std::io::Error::new(error.kind(), error.to_string()))
+            }
+            Error::FrameTooLarge { size, max } => Error::FrameTooLarge {
+                size: *size,
+                max: *max,
+            },
+            Error::ConnectionClosed => Error::ConnectionClosed,
+        }
+    }
 }
 
 impl From<std::io::Error> for Error {
@@ -8,3 +45,13 @@ impl From<std::io::Error> for Error {
         Error::IOError(error)
     }
 }
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<ws::Error> for Error {
+    fn from(error: ws::Error) -> Error {
+        match error.kind {
+            ws::ErrorKind::Io(error) => Error::IOError(error),
+            _ => Error::IOError(std::io::Error::other(error)),
+        }
+    }
+}
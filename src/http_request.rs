@@ -1,21 +1,73 @@
 //! Async http requests.
 
+use std::time::Duration;
+
 #[cfg(target_arch = "wasm32")]
 use sapp_jsutils::JsObject;
 
+#[cfg(not(target_arch = "wasm32"))]
+mod cache;
+mod client;
+#[cfg(not(target_arch = "wasm32"))]
+mod download;
+mod retry;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use cache::HttpCache;
+pub use client::HttpClient;
+#[cfg(not(target_arch = "wasm32"))]
+pub use download::DownloadBuilder;
+pub use retry::Retry;
+
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum Method {
     Post,
     Put,
     Get,
     Delete,
+    Patch,
+    Head,
+    Options,
+}
+
+impl Method {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Get => "GET",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum HttpError {
     IOError,
     #[cfg(not(target_arch = "wasm32"))]
-    UreqError(ureq::Error),
+    UreqError(Box<ureq::Error>),
+    /// The server could not be reached. Browsers report CORS failures
+    /// this way too, without telling them apart.
+    NetworkError,
+    TimedOut,
+    Aborted,
+    /// The body was expected to be text, but is not valid UTF-8.
+    InvalidUtf8(std::str::Utf8Error),
+    #[cfg(feature = "nanoserde")]
+    InvalidJson(nanoserde::DeJsonErr),
+    /// A status outside of the 200-299 range, from the typed helpers
+    /// like `Request::try_recv_json`.
+    Status(Response),
+    /// The downloaded file did not match the expected SHA-256 digest,
+    /// see `DownloadBuilder::sha256`.
+    #[cfg(not(target_arch = "wasm32"))]
+    DigestMismatch,
+    /// The thread running the request panicked.
+    #[cfg(not(target_arch = "wasm32"))]
+    Panicked,
 }
 
 impl std::fmt::Display for HttpError {
@@ -24,67 +76,393 @@ impl std::fmt::Display for HttpError {
             HttpError::IOError => write!(f, "IOError"),
             #[cfg(not(target_arch = "wasm32"))]
             HttpError::UreqError(error) => write!(f, "Ureq error: {}", error),
+            HttpError::NetworkError => write!(f, "Network error, or blocked by CORS"),
+            HttpError::TimedOut => write!(f, "Timed out"),
+            HttpError::Aborted => write!(f, "Aborted"),
+            HttpError::InvalidUtf8(error) => write!(f, "Invalid UTF-8 body: {}", error),
+            #[cfg(feature = "nanoserde")]
+            HttpError::InvalidJson(error) => write!(f, "Invalid JSON body: {}", error),
+            HttpError::Status(response) => write!(f, "HTTP status {}", response.status),
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpError::DigestMismatch => write!(f, "Downloaded file does not match its digest"),
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpError::Panicked => write!(f, "Request thread panicked"),
         }
     }
 }
 impl From<std::io::Error> for HttpError {
-    fn from(_error: std::io::Error) -> HttpError {
-        HttpError::IOError
+    fn from(error: std::io::Error) -> HttpError {
+        match error.kind() {
+            std::io::ErrorKind::TimedOut => HttpError::TimedOut,
+            _ => HttpError::IOError,
+        }
     }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 impl From<ureq::Error> for HttpError {
     fn from(error: ureq::Error) -> HttpError {
-        HttpError::UreqError(error)
+        use std::error::Error;
+
+        // Same as on the web, where timeouts are all we know about.
+        let source = error
+            .source()
+            .and_then(|source| source.downcast_ref::<std::io::Error>());
+        if matches!(
+            source.map(|source| source.kind()),
+            Some(std::io::ErrorKind::TimedOut)
+        ) {
+            return HttpError::TimedOut;
+        }
+
+        HttpError::UreqError(Box::new(error))
+    }
+}
+
+/// How much of the response body has arrived so far.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Progress {
+    pub received: u64,
+    /// From the `Content-Length` header, unknown for chunked responses.
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Between 0.0 and 1.0, if the total is known.
+    pub fn fraction(&self) -> Option<f32> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some((self.received as f64 / total as f64).min(1.0) as f32),
+            None => None,
+        }
+    }
+}
+
+/// State shared between a `Request` and its thread.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct Transfer {
+    cancelled: std::sync::atomic::AtomicBool,
+    progress: std::sync::Mutex<Progress>,
+}
+
+/// A finished request, whatever its status code.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    /// In the order the server sent them, names may repeat.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Status code in the 200-299 range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Value of the first header with the given name, case insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> Result<&str, HttpError> {
+        std::str::from_utf8(&self.body).map_err(HttpError::InvalidUtf8)
+    }
+
+    #[cfg(feature = "nanoserde")]
+    pub fn json<T: nanoserde::DeJson>(&self) -> Result<T, HttpError> {
+        nanoserde::DeJson::deserialize_json(self.text()?).map_err(HttpError::InvalidJson)
+    }
+
+    /// Reads the body in chunks, to report the progress and to give up
+    /// as soon as the request is cancelled.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_ureq(response: ureq::Response, transfer: &Transfer) -> Result<Response, HttpError> {
+        use std::io::Read;
+        use std::sync::atomic::Ordering;
+
+        let status = response.status();
+        let headers = Response::ureq_headers(&response);
+        let total = response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok());
+        *transfer.progress.lock().unwrap() = Progress { received: 0, total };
+
+        let mut body = vec![];
+        let mut reader = response.into_reader();
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            if transfer.cancelled.load(Ordering::Relaxed) {
+                return Err(HttpError::Aborted);
+            }
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    body.extend_from_slice(&chunk[..n]);
+                    transfer.progress.lock().unwrap().received = body.len() as u64;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn ureq_headers(response: &ureq::Response) -> Vec<(String, String)> {
+        let mut headers = vec![];
+        for name in response.headers_names() {
+            // Repeated headers have their name listed once per value.
+            if headers
+                .iter()
+                .any(|(header, _): &(String, String)| *header == name)
+            {
+                continue;
+            }
+            for value in response.all(&name) {
+                headers.push((name.clone(), value.to_owned()));
+            }
+        }
+
+        headers
+    }
+
+    /// Parse the headers as given by `XMLHttpRequest.getAllResponseHeaders`.
+    #[cfg(target_arch = "wasm32")]
+    fn parse_headers(headers: &str) -> Vec<(String, String)> {
+        headers
+            .split("\r\n")
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+            .collect()
     }
 }
 
 #[cfg(target_arch = "wasm32")]
 extern "C" {
-    fn http_make_request(scheme: i32, url: JsObject, body: JsObject, headers: JsObject) -> i32;
+    fn http_make_request(
+        method: JsObject,
+        url: JsObject,
+        body: JsObject,
+        headers: JsObject,
+        timeout_ms: u32,
+    ) -> i32;
     fn http_try_recv(cid: i32) -> JsObject;
+    fn http_progress_received(cid: i32) -> f64;
+    fn http_progress_total(cid: i32) -> f64;
+    fn http_cancel(cid: i32);
+    fn http_forget(cid: i32);
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 pub struct Request {
-    rx: std::sync::mpsc::Receiver<Result<String, HttpError>>,
+    rx: std::sync::mpsc::Receiver<Result<Response, HttpError>>,
+    transfer: std::sync::Arc<Transfer>,
+    /// The result was already handed out.
+    done: bool,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 impl Request {
-    pub fn try_recv(&mut self) -> Option<Result<String, HttpError>> {
-        self.rx.try_recv().ok()
+    /// Error statuses are still an `Ok` response, check `Response::status`.
+    pub fn try_recv(&mut self) -> Option<Result<Response, HttpError>> {
+        use std::sync::mpsc::TryRecvError;
+
+        if self.done {
+            return None;
+        }
+        let result = match self.rx.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            // The job was dropped without sending anything.
+            Err(TryRecvError::Disconnected) => Err(HttpError::Panicked),
+        };
+        self.done = true;
+
+        Some(result)
+    }
+
+    /// Abort the request, `try_recv` will return `HttpError::Aborted`.
+    ///
+    /// The request thread stops at the next chunk of the body, the connection
+    /// itself is only bounded by `RequestBuilder::timeout`.
+    pub fn cancel(&mut self) {
+        use std::sync::atomic::Ordering;
+
+        self.transfer.cancelled.store(true, Ordering::Relaxed);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _ = tx.send(Err(HttpError::Aborted));
+        self.rx = rx;
+    }
+
+    /// Progress of the response body download, for loading bars.
+    pub fn progress(&self) -> Progress {
+        *self.transfer.progress.lock().unwrap()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.transfer
+            .cancelled
+            .store(true, std::sync::atomic::Ordering::Relaxed);
     }
 }
 
+/// What a wasm request needs to make another attempt.
+#[cfg(target_arch = "wasm32")]
+struct Retrying {
+    builder: RequestBuilder,
+    policy: Retry,
+    backoff: crate::backoff::Backoff,
+    /// When to make the next attempt, in `backoff::now()` time.
+    next_attempt: Option<f64>,
+}
+
 #[cfg(target_arch = "wasm32")]
 pub struct Request {
     cid: i32,
+    retrying: Option<Retrying>,
+    /// Cancelled while waiting for the next attempt.
+    aborted: bool,
 }
 
 #[cfg(target_arch = "wasm32")]
 impl Request {
-    pub fn try_recv(&mut self) -> Option<Result<String, HttpError>> {
+    /// Error statuses are still an `Ok` response, check `Response::status`.
+    pub fn try_recv(&mut self) -> Option<Result<Response, HttpError>> {
+        if self.aborted {
+            self.aborted = false;
+            return Some(Err(HttpError::Aborted));
+        }
+
+        // No threads to sleep on, the next attempt waits for a later call.
+        if let Some(retrying) = &mut self.retrying {
+            if let Some(time) = retrying.next_attempt {
+                if crate::backoff::now() < time {
+                    return None;
+                }
+                retrying.next_attempt = None;
+                unsafe { http_forget(self.cid) };
+                self.cid = retrying.builder.start();
+            }
+        }
+
+        let result = self.poll()?;
+        if let Some(retrying) = &mut self.retrying {
+            if let Some(delay) = retrying.policy.delay(&mut retrying.backoff, &result) {
+                retrying.next_attempt = Some(crate::backoff::now() + delay.as_secs_f64());
+                return None;
+            }
+        }
+
+        Some(result)
+    }
+
+    fn poll(&mut self) -> Option<Result<Response, HttpError>> {
         let js_obj = unsafe { http_try_recv(self.cid) };
 
         if js_obj.is_nil() == false {
-            let mut buf = vec![];
-            js_obj.to_byte_buffer(&mut buf);
+            // Kept in sync with `http_make_request` in quad-net.js
+            match js_obj.field_u32("error") {
+                0 => {}
+                2 => return Some(Err(HttpError::TimedOut)),
+                3 => return Some(Err(HttpError::Aborted)),
+                _ => return Some(Err(HttpError::NetworkError)),
+            }
+
+            let mut body = vec![];
+            js_obj.field("body").to_byte_buffer(&mut body);
+            let mut headers = String::new();
+            js_obj.field("headers").to_string(&mut headers);
 
-            let res = std::str::from_utf8(&buf).unwrap().to_owned();
-            return Some(Ok(res));
+            return Some(Ok(Response {
+                status: js_obj.field_u32("status") as u16,
+                headers: Response::parse_headers(&headers),
+                body,
+            }));
         }
 
         None
     }
+
+    /// Abort the request, `try_recv` will return `HttpError::Aborted`.
+    pub fn cancel(&mut self) {
+        if let Some(retrying) = self.retrying.take() {
+            // Nothing in flight to abort.
+            if retrying.next_attempt.is_some() {
+                self.aborted = true;
+                return;
+            }
+        }
+        unsafe { http_cancel(self.cid) };
+    }
+
+    /// Progress of the response body download, for loading bars.
+    pub fn progress(&self) -> Progress {
+        let received = unsafe { http_progress_received(self.cid) };
+        // Negative while the total is unknown.
+        let total = unsafe { http_progress_total(self.cid) };
+
+        Progress {
+            received: received as u64,
+            total: if total >= 0.0 {
+                Some(total as u64)
+            } else {
+                None
+            },
+        }
+    }
 }
 
+impl Request {
+    /// Decode a successful response as JSON, other statuses are
+    /// an `HttpError::Status`.
+    #[cfg(feature = "nanoserde")]
+    pub fn try_recv_json<T: nanoserde::DeJson>(&mut self) -> Option<Result<T, HttpError>> {
+        let response = match self.try_recv()? {
+            Ok(response) => response,
+            Err(error) => return Some(Err(error)),
+        };
+        if !response.is_success() {
+            return Some(Err(HttpError::Status(response)));
+        }
+
+        Some(response.json())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for Request {
+    fn drop(&mut self) {
+        unsafe { http_forget(self.cid) };
+    }
+}
+
+#[derive(Clone)]
 pub struct RequestBuilder {
     url: String,
     method: Method,
     headers: Vec<(String, String)>,
-    body: Option<String>,
+    body: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    retry: Option<Retry>,
+    /// Set for requests made through an `HttpClient`.
+    #[cfg(not(target_arch = "wasm32"))]
+    backend: Option<client::Backend>,
+    #[cfg(not(target_arch = "wasm32"))]
+    cache: Option<HttpCache>,
 }
 
 impl RequestBuilder {
@@ -94,6 +472,12 @@ impl RequestBuilder {
             method: Method::Get,
             headers: vec![],
             body: None,
+            timeout: None,
+            retry: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            backend: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            cache: None,
         }
     }
 
@@ -111,67 +495,220 @@ impl RequestBuilder {
     }
 
     pub fn body(self, body: &str) -> RequestBuilder {
+        self.body_bytes(body.as_bytes().to_vec())
+    }
+
+    pub fn body_bytes(self, body: Vec<u8>) -> RequestBuilder {
+        RequestBuilder {
+            body: Some(body),
+            ..self
+        }
+    }
+
+    /// Serialize `value` as the body, with a JSON `Content-Type`.
+    #[cfg(feature = "nanoserde")]
+    pub fn json<T: nanoserde::SerJson>(mut self, value: &T) -> RequestBuilder {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case("Content-Type"));
+
+        self.header("Content-Type", "application/json")
+            .body(&nanoserde::SerJson::serialize_json(value))
+    }
+
+    /// Give up with `HttpError::TimedOut` if the whole request takes longer.
+    pub fn timeout(self, timeout: Duration) -> RequestBuilder {
         RequestBuilder {
-            body: Some(body.to_owned()),
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Make more attempts if the request fails, see `Retry`.
+    ///
+    /// `POST` and `PATCH` requests are only retried with `Retry::non_idempotent`.
+    pub fn retry(self, retry: Retry) -> RequestBuilder {
+        RequestBuilder {
+            retry: Some(retry),
+            ..self
+        }
+    }
+
+    /// Revalidate and store the response in `cache`, see `HttpCache`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn cache(self, cache: &HttpCache) -> RequestBuilder {
+        RequestBuilder {
+            cache: Some(cache.clone()),
             ..self
         }
     }
 
     #[cfg(not(target_arch = "wasm32"))]
     pub fn send(self) -> Request {
-        use std::sync::mpsc::channel;
+        let cache = match &self.cache {
+            Some(cache) if HttpCache::caches(self.method) => Some(cache.clone()),
+            _ => None,
+        };
+
+        let retry = self
+            .retry
+            .clone()
+            .filter(|retry| retry.applies(self.method));
 
-        let (tx, rx) = channel();
+        self.spawn(move |request, body, transfer| {
+            let attempt = || {
+                if let Some(cache) = &cache {
+                    return cache.send(request.clone(), transfer);
+                }
 
-        std::thread::spawn(move || {
-            let method = match self.method {
-                Method::Post => ureq::post,
-                Method::Put => ureq::put,
-                Method::Get => ureq::get,
-                Method::Delete => ureq::delete,
+                let response = if let Some(body) = &body {
+                    request.clone().send_bytes(body)
+                } else {
+                    request.clone().call()
+                };
+                match response {
+                    Ok(response) | Err(ureq::Error::Status(_, response)) => {
+                        Response::from_ureq(response, transfer)
+                    }
+                    Err(err) => Err(err.into()),
+                }
             };
 
-            let mut request = method(&self.url);
-            for (header, value) in self.headers {
-                request = request.set(&header, &value)
-            }
-            let response: Result<String, HttpError> = if let Some(body) = self.body {
-                request.send_string(&body)
-            } else {
-                request.call()
+            let retry = match retry {
+                Some(retry) => retry,
+                None => return attempt(),
+            };
+            let mut backoff = retry.backoff();
+            loop {
+                let result = attempt();
+                let delay = match retry.delay(&mut backoff, &result) {
+                    Some(delay) => delay,
+                    None => return result,
+                };
+
+                // In short naps, to notice a cancellation.
+                let deadline = match std::time::Instant::now().checked_add(delay) {
+                    Some(deadline) => deadline,
+                    None => return result,
+                };
+                while let Some(left) = deadline.checked_duration_since(std::time::Instant::now()) {
+                    if transfer
+                        .cancelled
+                        .load(std::sync::atomic::Ordering::Relaxed)
+                    {
+                        return Err(HttpError::Aborted);
+                    }
+                    std::thread::sleep(left.min(Duration::from_millis(50)));
+                }
             }
-            .map_err(|err| err.into())
-            .and_then(|response| response.into_string().map_err(|err| err.into()));
+        })
+    }
+
+    /// Run `job` with the ureq request and the body on the client workers,
+    /// or on a thread of its own.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn<F>(self, job: F) -> Request
+    where
+        F: FnOnce(ureq::Request, Option<Vec<u8>>, &Transfer) -> Result<Response, HttpError>
+            + Send
+            + 'static,
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let transfer = std::sync::Arc::new(Transfer::default());
+
+        let mut request = match &self.backend {
+            Some(backend) => backend.agent.request(self.method.as_str(), &self.url),
+            None => ureq::request(self.method.as_str(), &self.url),
+        };
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        for (header, value) in &self.headers {
+            request = request.set(header, value)
+        }
+
+        let body = self.body;
+        let job = {
+            let transfer = transfer.clone();
+            move || {
+                let response = job(request, body, &transfer);
 
-            tx.send(response).unwrap();
-        });
+                // Nobody to send to if the request was dropped.
+                let _ = tx.send(response);
+            }
+        };
+        match self.backend {
+            Some(backend) => backend.pool.execute(job),
+            None => {
+                std::thread::spawn(job);
+            }
+        }
 
-        Request { rx }
+        Request {
+            rx,
+            transfer,
+            done: false,
+        }
     }
 
     #[cfg(target_arch = "wasm32")]
     pub fn send(&self) -> Request {
-        let scheme = match self.method {
-            Method::Post => 0,
-            Method::Put => 1,
-            Method::Get => 2,
-            Method::Delete => 3,
+        let retrying = match &self.retry {
+            Some(retry) if retry.applies(self.method) => Some(Retrying {
+                builder: self.clone(),
+                policy: retry.clone(),
+                backoff: retry.backoff(),
+                next_attempt: None,
+            }),
+            _ => None,
         };
 
+        Request {
+            cid: self.start(),
+            retrying,
+            aborted: false,
+        }
+    }
+
+    /// Hand the request over to the browser.
+    #[cfg(target_arch = "wasm32")]
+    fn start(&self) -> i32 {
         let headers = JsObject::object();
 
         for (header, value) in &self.headers {
             headers.set_field_string(&header, &value);
         }
 
-        let cid = unsafe {
+        unsafe {
             http_make_request(
-                scheme,
+                JsObject::string(self.method.as_str()),
                 JsObject::string(&self.url),
-                JsObject::string(&self.body.as_ref().map(|s| s.as_str()).unwrap_or("")),
+                JsObject::buffer(self.body.as_deref().unwrap_or(&[])),
                 headers,
+                // Anything longer than the ~50 days that fit is as good as none.
+                self.timeout.map_or(0, |timeout| {
+                    timeout.as_millis().min(u32::MAX as u128) as u32
+                }),
             )
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_job_is_reported_once() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut request = Request {
+            rx,
+            transfer: Default::default(),
+            done: false,
         };
-        Request { cid }
+        assert!(request.try_recv().is_none());
+
+        drop(tx);
+        assert!(matches!(request.try_recv(), Some(Err(HttpError::Panicked))));
+        assert!(request.try_recv().is_none());
     }
 }
@@ -0,0 +1,299 @@
+//! Opt-in on-disk cache of responses, for the desktop backend.
+//!
+//! Every entry is two files named after the SHA-256 of the method and the url:
+//! `<key>.body` and `<key>.meta`, the latter holding the status and headers.
+//! Stored responses are revalidated with `If-None-Match`/`If-Modified-Since`
+//! and served again on `304 Not Modified`, or when the server can't be reached.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use super::{HttpError, Method, Response, Transfer};
+
+struct Inner {
+    dir: PathBuf,
+    max_size: u64,
+    /// Serializes reads, writes and evictions between requests.
+    lock: Mutex<()>,
+}
+
+/// A directory of cached responses, shared by all its clones.
+///
+/// Only `GET` and `HEAD` requests with a successful response are cached,
+/// unless the server asks for `Cache-Control: no-store`.
+#[derive(Clone)]
+pub struct HttpCache {
+    inner: Arc<Inner>,
+}
+
+impl HttpCache {
+    /// Once the entries grow past `max_size` bytes, the least recently used
+    /// ones are evicted.
+    pub fn new<P: AsRef<Path>>(dir: P, max_size: u64) -> HttpCache {
+        HttpCache {
+            inner: Arc::new(Inner {
+                dir: dir.as_ref().to_owned(),
+                max_size,
+                lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Remove all the entries.
+    pub fn clear(&self) {
+        let _lock = self.inner.lock.lock().unwrap();
+        for (path, _, _) in self.entries() {
+            let _ = fs::remove_file(path.with_extension("body"));
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    pub(super) fn caches(method: Method) -> bool {
+        matches!(method, Method::Get | Method::Head)
+    }
+
+    fn path(&self, request: &ureq::Request) -> PathBuf {
+        let key: String = Sha256::digest(format!("{} {}", request.method(), request.url()))
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        self.inner.dir.join(key).with_extension("meta")
+    }
+
+    /// Make the request, revalidating the stored response if there is one.
+    pub(super) fn send(
+        &self,
+        request: ureq::Request,
+        transfer: &Transfer,
+    ) -> Result<Response, HttpError> {
+        let meta = self.path(&request);
+        let cached = {
+            // Or a concurrent `store` could pair the old metadata with a new body.
+            let _lock = self.inner.lock.lock().unwrap();
+            load(&meta)
+        };
+
+        let mut request = request;
+        if let Some(cached) = &cached {
+            if let Some(etag) = cached.header("ETag") {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(modified) = cached.header("Last-Modified") {
+                request = request.set("If-Modified-Since", modified);
+            }
+        }
+
+        let response = match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            // Offline, the stale response is better than nothing.
+            Err(ureq::Error::Transport(err)) => {
+                return match cached {
+                    Some(cached) => Ok(cached),
+                    None => Err(ureq::Error::Transport(err).into()),
+                }
+            }
+        };
+        if let (304, Some(cached)) = (response.status(), cached) {
+            self.touch(&meta, &cached);
+            return Ok(cached);
+        }
+        let response = Response::from_ureq(response, transfer)?;
+
+        let no_store = matches!(
+            response.header("Cache-Control"),
+            Some(control) if control.contains("no-store")
+        );
+        if response.is_success() && !no_store {
+            // Failing to cache is no reason to fail the request.
+            let _ = self.store(&meta, &response);
+        }
+
+        Ok(response)
+    }
+
+    fn store(&self, meta: &Path, response: &Response) -> std::io::Result<()> {
+        let _lock = self.inner.lock.lock().unwrap();
+
+        fs::create_dir_all(&self.inner.dir)?;
+        // Never leave the new body paired with the old metadata.
+        let _ = fs::remove_file(meta);
+        fs::write(meta.with_extension("body"), &response.body)?;
+        fs::write(meta, serialize(response))?;
+
+        self.evict();
+        Ok(())
+    }
+
+    /// Rewrite the metadata, its modification time is the time of last use.
+    fn touch(&self, meta: &Path, response: &Response) {
+        let _lock = self.inner.lock.lock().unwrap();
+        let _ = fs::write(meta, serialize(response));
+    }
+
+    /// `(meta path, last use, size)` of every entry.
+    fn entries(&self) -> Vec<(PathBuf, SystemTime, u64)> {
+        let dir = match fs::read_dir(&self.inner.dir) {
+            Ok(dir) => dir,
+            Err(_) => return vec![],
+        };
+
+        dir.filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| matches!(path.extension(), Some(extension) if extension == "meta"))
+            .filter_map(|path| {
+                let metadata = fs::metadata(&path).ok()?;
+                let body = fs::metadata(path.with_extension("body"))
+                    .map(|body| body.len())
+                    .unwrap_or(0);
+                let used = metadata.modified().ok()?;
+                Some((path, used, metadata.len() + body))
+            })
+            .collect()
+    }
+
+    fn evict(&self) {
+        let mut entries = self.entries();
+        let mut size: u64 = entries.iter().map(|(_, _, size)| size).sum();
+        entries.sort_by_key(|(_, used, _)| *used);
+
+        for (path, _, entry_size) in entries {
+            if size <= self.inner.max_size {
+                break;
+            }
+            let _ = fs::remove_file(&path);
+            let _ = fs::remove_file(path.with_extension("body"));
+            size -= entry_size;
+        }
+    }
+}
+
+/// The status line, then a header per line.
+fn serialize(response: &Response) -> String {
+    let mut meta = format!("{}\n", response.status);
+    for (name, value) in &response.headers {
+        meta.push_str(&format!("{}: {}\n", name, value));
+    }
+
+    meta
+}
+
+fn load(meta: &Path) -> Option<Response> {
+    let text = fs::read_to_string(meta).ok()?;
+    let body = fs::read(meta.with_extension("body")).ok()?;
+
+    let mut lines = text.lines();
+    let status = lines.next()?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(": "))
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+
+    Some(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// An empty cache in a directory of its own.
+    fn cache(name: &str, max_size: u64) -> HttpCache {
+        let dir =
+            std::env::temp_dir().join(format!("quad-net-cache-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        HttpCache::new(dir, max_size)
+    }
+
+    fn response(body: &[u8]) -> Response {
+        Response {
+            status: 200,
+            headers: vec![
+                ("ETag".to_owned(), "\"v1\"".to_owned()),
+                ("X-Note".to_owned(), "a: b".to_owned()),
+                ("X-Note".to_owned(), "".to_owned()),
+            ],
+            body: body.to_vec(),
+        }
+    }
+
+    fn names(cache: &HttpCache) -> Vec<String> {
+        let mut names: Vec<String> = cache
+            .entries()
+            .iter()
+            .map(|(path, _, _)| path.file_stem().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = cache("round-trip", u64::MAX);
+        let meta = cache.inner.dir.join("entry.meta");
+        let original = response(b"\0binary\nbody");
+
+        cache.store(&meta, &original).unwrap();
+        let loaded = load(&meta).unwrap();
+
+        assert_eq!(loaded.status, original.status);
+        assert_eq!(loaded.headers, original.headers);
+        assert_eq!(loaded.body, original.body);
+
+        // A body without its metadata is no entry.
+        fs::remove_file(&meta).unwrap();
+        assert!(load(&meta).is_none());
+        let _ = fs::remove_dir_all(&cache.inner.dir);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache("evict", u64::MAX);
+        let now = SystemTime::now();
+        for (name, age) in [("old", 30), ("new", 10), ("newest", 0)] {
+            let meta = cache.inner.dir.join(name).with_extension("meta");
+            cache.store(&meta, &response(&[0; 1000])).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&meta)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+        let entry_size = cache.entries()[0].2;
+
+        let small = HttpCache::new(&cache.inner.dir, entry_size * 2);
+        small.evict();
+        assert_eq!(names(&cache), vec!["new", "newest"]);
+
+        let smaller = HttpCache::new(&cache.inner.dir, entry_size);
+        smaller.evict();
+        assert_eq!(names(&cache), vec!["newest"]);
+        assert!(!cache.inner.dir.join("old.body").exists());
+        let _ = fs::remove_dir_all(&cache.inner.dir);
+    }
+
+    #[test]
+    fn clear_removes_everything() {
+        let cache = cache("clear", u64::MAX);
+        for name in ["a", "b"] {
+            let meta = cache.inner.dir.join(name).with_extension("meta");
+            cache.store(&meta, &response(b"body")).unwrap();
+        }
+        assert_eq!(names(&cache), vec!["a", "b"]);
+
+        cache.clear();
+        assert!(names(&cache).is_empty());
+        assert_eq!(fs::read_dir(&cache.inner.dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(&cache.inner.dir);
+    }
+}
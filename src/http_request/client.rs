@@ -0,0 +1,225 @@
+//! Shared configuration and connections for a bunch of requests.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{mpsc, Arc, Mutex};
+
+use super::RequestBuilder;
+
+#[cfg(not(target_arch = "wasm32"))]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running the requests of an `HttpClient`.
+///
+/// Threads are spawned on demand and exit once the last client clone is gone.
+/// A panicking job does not take its thread down with it.
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) struct WorkerPool {
+    jobs: Mutex<mpsc::Sender<Job>>,
+    receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
+    size: usize,
+    spawned: Mutex<usize>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl WorkerPool {
+    fn new(size: usize) -> WorkerPool {
+        let (tx, rx) = mpsc::channel();
+
+        WorkerPool {
+            jobs: Mutex::new(tx),
+            receiver: Arc::new(Mutex::new(rx)),
+            size: size.max(1),
+            spawned: Mutex::new(0),
+        }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.jobs.lock().unwrap().send(Box::new(job));
+
+        let mut spawned = self.spawned.lock().unwrap();
+        if *spawned < self.size {
+            *spawned += 1;
+            let receiver = self.receiver.clone();
+            std::thread::spawn(move || loop {
+                // The lock is released before running the job.
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => {
+                        // The request sees its channel disconnected and reports it.
+                        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+    }
+}
+
+/// What a request needs from the client that made it.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone)]
+pub(super) struct Backend {
+    /// Keeps connections alive between requests.
+    pub agent: ureq::Agent,
+    pub pool: Arc<WorkerPool>,
+}
+
+/// Requests sharing a base url, default headers and, on desktop,
+/// a connection pool and a bounded number of worker threads.
+///
+/// Cheap to clone, clones share their connections and workers.
+///
+/// ```ignore
+/// let client = HttpClient::new()
+///     .base_url("https://example.com/api")
+///     .header("Authorization", "Bearer secret");
+/// let request = client.request("users/1").send();
+/// ```
+#[derive(Clone)]
+pub struct HttpClient {
+    base_url: Option<String>,
+    headers: Vec<(String, String)>,
+    retry: Option<super::Retry>,
+    #[cfg(not(target_arch = "wasm32"))]
+    backend: Backend,
+    #[cfg(not(target_arch = "wasm32"))]
+    cache: Option<super::HttpCache>,
+}
+
+impl Default for HttpClient {
+    fn default() -> HttpClient {
+        HttpClient::new()
+    }
+}
+
+impl HttpClient {
+    pub const DEFAULT_WORKERS: usize = 4;
+
+    pub fn new() -> HttpClient {
+        HttpClient {
+            base_url: None,
+            headers: vec![],
+            retry: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            backend: Backend {
+                agent: ureq::Agent::new(),
+                pool: Arc::new(WorkerPool::new(Self::DEFAULT_WORKERS)),
+            },
+            #[cfg(not(target_arch = "wasm32"))]
+            cache: None,
+        }
+    }
+
+    /// Prepended to relative urls given to `request`.
+    pub fn base_url(mut self, base_url: &str) -> HttpClient {
+        self.base_url = Some(base_url.to_owned());
+        self
+    }
+
+    /// Header sent with every request.
+    pub fn header(mut self, header: &str, value: &str) -> HttpClient {
+        self.headers.push((header.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Browsers always send their own user agent, so this does nothing on the web.
+    pub fn user_agent(self, _user_agent: &str) -> HttpClient {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let mut client = self;
+            client.backend.agent = ureq::AgentBuilder::new().user_agent(_user_agent).build();
+            client
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        self
+    }
+
+    /// How many requests may run at the same time, the rest wait in line.
+    /// Browsers manage their own connections, so this does nothing on the web.
+    pub fn workers(self, _workers: usize) -> HttpClient {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let mut client = self;
+            client.backend.pool = Arc::new(WorkerPool::new(_workers));
+            client
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        self
+    }
+
+    /// Retry policy of all the requests, see `Retry`.
+    pub fn retry(mut self, retry: super::Retry) -> HttpClient {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Cache the responses of all the requests, see `HttpCache`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn cache(mut self, cache: super::HttpCache) -> HttpClient {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Start a request to `url`, relative to the base url unless it is absolute.
+    pub fn request(&self, url: &str) -> RequestBuilder {
+        let url = match &self.base_url {
+            Some(base_url) if !url.contains("://") => format!(
+                "{}/{}",
+                base_url.trim_end_matches('/'),
+                url.trim_start_matches('/')
+            ),
+            _ => url.to_owned(),
+        };
+
+        let mut builder = RequestBuilder::new(&url);
+        builder.headers = self.headers.clone();
+        builder.retry = self.retry.clone();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            builder.backend = Some(self.backend.clone());
+            builder.cache = self.cache.clone();
+        }
+        builder
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn pool_survives_panicking_jobs() {
+        let pool = WorkerPool::new(2);
+        for _ in 0..4 {
+            pool.execute(|| panic!("job panicked"));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        for i in 0..4 {
+            let tx = tx.clone();
+            pool.execute(move || tx.send(i).unwrap());
+        }
+        let mut done: Vec<i32> = (0..4)
+            .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort_unstable();
+
+        assert_eq!(done, vec![0, 1, 2, 3]);
+        assert_eq!(*pool.spawned.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn joins_base_url() {
+        let url = |base: &str, url: &str| HttpClient::new().base_url(base).request(url).url;
+
+        assert_eq!(url("http://host/api", "items"), "http://host/api/items");
+        assert_eq!(url("http://host/api/", "items"), "http://host/api/items");
+        assert_eq!(url("http://host/api", "/items"), "http://host/api/items");
+        assert_eq!(url("http://host/api/", "/items"), "http://host/api/items");
+        assert_eq!(url("http://host/api", "https://other/x"), "https://other/x");
+        assert_eq!(HttpClient::new().request("/items").url, "/items");
+    }
+}
@@ -0,0 +1,294 @@
+//! Downloads streamed straight to disk, for files too big to keep in memory.
+//!
+//! The body goes to `<path>.part` first, along with the validator of the
+//! server's version of the file in `<path>.part.validator`. An interrupted
+//! download picks up from there with a `Range` request, the `If-Range` header
+//! makes the server send the whole file again if it changed in the meantime.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use sha2::{Digest, Sha256};
+
+use super::{HttpError, Progress, Request, RequestBuilder, Response, Transfer};
+
+impl RequestBuilder {
+    /// Stream the response body into a file at `path` instead of memory.
+    ///
+    /// The `Response` of the returned request has an empty body, the file is
+    /// only written to `path` once complete and verified. Statuses outside of
+    /// the 200-299 range leave the file alone.
+    ///
+    /// The body of the request is sent, but its `retry` policy and `cache` are
+    /// not used: an interrupted download resumes from its partial file the next
+    /// time it is started instead.
+    pub fn download_to_file<P: AsRef<Path>>(self, path: P) -> DownloadBuilder {
+        DownloadBuilder {
+            request: self,
+            path: path.as_ref().to_owned(),
+            sha256: None,
+        }
+    }
+}
+
+pub struct DownloadBuilder {
+    request: RequestBuilder,
+    path: PathBuf,
+    sha256: Option<String>,
+}
+
+impl DownloadBuilder {
+    /// Expected SHA-256 digest of the file, in hex. On a mismatch the download
+    /// is deleted and the request fails with `HttpError::DigestMismatch`.
+    pub fn sha256(mut self, digest: &str) -> DownloadBuilder {
+        self.sha256 = Some(digest.trim().to_ascii_lowercase());
+        self
+    }
+
+    pub fn send(self) -> Request {
+        let path = self.path;
+        let sha256 = self.sha256;
+
+        self.request.spawn(move |request, body, transfer| {
+            download(request, body.as_deref(), &path, sha256.as_deref(), transfer)
+        })
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(suffix);
+
+    PathBuf::from(name)
+}
+
+/// Strong validators only, a weak ETag is not allowed in `If-Range`.
+fn validator(response: &ureq::Response) -> Option<&str> {
+    match response.header("ETag") {
+        Some(etag) if !etag.starts_with("W/") => Some(etag),
+        _ => response.header("Last-Modified"),
+    }
+}
+
+/// `(start, total)` of a `Content-Range: bytes start-end/total` header.
+fn content_range(header: &str) -> Option<(u64, Option<u64>)> {
+    let range = header.strip_prefix("bytes ")?;
+    let (range, total) = range.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+
+    Some((start.trim().parse().ok()?, total.trim().parse().ok()))
+}
+
+/// What to do with the response to a download request.
+#[derive(Debug, PartialEq)]
+enum Action {
+    /// The server sent the rest of the partial file.
+    Append,
+    /// The server sent the whole file, start over.
+    Restart,
+    /// The partial file is of no use, delete it and ask again.
+    Discard,
+    /// Anything but a success, the download failed. A 304 is one too,
+    /// there is no body to replace the file with.
+    Fail,
+}
+
+/// `offset` is the size of the partial file, if resuming,
+/// `range_start` where the `Content-Range` of the response starts.
+fn action(status: u16, offset: Option<u64>, range_start: Option<u64>) -> Action {
+    match (status, offset) {
+        // The partial file is bigger than the one on the server.
+        (416, Some(_)) => Action::Discard,
+        (206, Some(offset)) if range_start == Some(offset) => Action::Append,
+        (206, Some(_)) => Action::Discard,
+        // Servers are free to ignore `Range`, or the file changed.
+        (200..=299, _) => Action::Restart,
+        _ => Action::Fail,
+    }
+}
+
+fn hash_file(file: &mut File, hasher: &mut Sha256) -> Result<(), HttpError> {
+    let mut chunk = [0; 64 * 1024];
+    loop {
+        match file.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => hasher.update(&chunk[..n]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn download(
+    request: ureq::Request,
+    body: Option<&[u8]>,
+    path: &Path,
+    sha256: Option<&str>,
+    transfer: &Transfer,
+) -> Result<Response, HttpError> {
+    let partial = with_suffix(path, ".part");
+    let validator_path = with_suffix(path, ".part.validator");
+    let discard = || {
+        let _ = fs::remove_file(&partial);
+        let _ = fs::remove_file(&validator_path);
+    };
+
+    loop {
+        // Nothing to resume without a validator, the file might have changed.
+        let resume = match (fs::metadata(&partial), fs::read_to_string(&validator_path)) {
+            (Ok(metadata), Ok(validator)) if metadata.len() > 0 => {
+                Some((metadata.len(), validator))
+            }
+            _ => None,
+        };
+
+        // Ranges of a compressed body would not line up with the file on disk.
+        let mut request = request.clone().set("Accept-Encoding", "identity");
+        if let Some((offset, validator)) = &resume {
+            request = request
+                .set("Range", &format!("bytes={}-", offset))
+                .set("If-Range", validator);
+        }
+        let result = match body {
+            Some(body) => request.send_bytes(body),
+            None => request.call(),
+        };
+        let response = match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => return Err(err.into()),
+        };
+
+        let status = response.status();
+        let headers = Response::ureq_headers(&response);
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        let range = response.header("Content-Range").and_then(content_range);
+        let offset = resume.as_ref().map(|(offset, _)| *offset);
+        let mut hasher = sha256.map(|_| Sha256::new());
+
+        let (mut file, offset, total) = match action(status, offset, range.map(|range| range.0)) {
+            Action::Discard => {
+                discard();
+                continue;
+            }
+            Action::Fail => {
+                return Ok(Response {
+                    status,
+                    headers,
+                    body: vec![],
+                })
+            }
+            Action::Append => {
+                let offset = offset.unwrap_or(0);
+                let mut file = OpenOptions::new().read(true).append(true).open(&partial)?;
+                if let Some(hasher) = &mut hasher {
+                    hash_file(&mut file, hasher)?;
+                }
+                let total = range
+                    .and_then(|range| range.1)
+                    .or_else(|| length.map(|length| offset + length));
+                (file, offset, total)
+            }
+            Action::Restart => {
+                let file = File::create(&partial)?;
+                match validator(&response) {
+                    Some(validator) => fs::write(&validator_path, validator)?,
+                    None => {
+                        let _ = fs::remove_file(&validator_path);
+                    }
+                }
+                (file, 0, length)
+            }
+        };
+
+        *transfer.progress.lock().unwrap() = Progress {
+            received: offset,
+            total,
+        };
+
+        let mut received = offset;
+        let mut reader = response.into_reader();
+        let mut chunk = [0; 64 * 1024];
+        loop {
+            if transfer.cancelled.load(Ordering::Relaxed) {
+                return Err(HttpError::Aborted);
+            }
+            match reader.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    file.write_all(&chunk[..n])?;
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&chunk[..n]);
+                    }
+                    received += n as u64;
+                    transfer.progress.lock().unwrap().received = received;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        file.flush()?;
+        drop(file);
+
+        if let (Some(hasher), Some(expected)) = (hasher, sha256) {
+            let digest: String = hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            if digest != expected {
+                discard();
+                return Err(HttpError::DigestMismatch);
+            }
+        }
+
+        fs::rename(&partial, path)?;
+        let _ = fs::remove_file(&validator_path);
+
+        return Ok(Response {
+            status,
+            headers,
+            body: vec![],
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_content_range() {
+        assert_eq!(content_range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(content_range("bytes 0-0/1"), Some((0, Some(1))));
+        assert_eq!(content_range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(content_range("bytes */200"), None);
+        assert_eq!(content_range("items 0-9/10"), None);
+        assert_eq!(content_range(""), None);
+    }
+
+    #[test]
+    fn resume_actions() {
+        // A fresh download.
+        assert_eq!(action(200, None, None), Action::Restart);
+        assert_eq!(action(404, None, None), Action::Fail);
+        assert_eq!(action(416, None, None), Action::Fail);
+        assert_eq!(action(100, None, None), Action::Fail);
+        assert_eq!(action(301, None, None), Action::Fail);
+        assert_eq!(action(304, None, None), Action::Fail);
+
+        // Resuming from byte 100.
+        assert_eq!(action(206, Some(100), Some(100)), Action::Append);
+        assert_eq!(action(206, Some(100), Some(0)), Action::Discard);
+        assert_eq!(action(206, Some(100), None), Action::Discard);
+        assert_eq!(action(416, Some(100), None), Action::Discard);
+        // Range ignored, or If-Range did not match.
+        assert_eq!(action(200, Some(100), None), Action::Restart);
+        assert_eq!(action(304, Some(100), None), Action::Fail);
+        assert_eq!(action(503, Some(100), None), Action::Fail);
+    }
+}
@@ -0,0 +1,258 @@
+//! Retrying failed requests with exponential backoff.
+
+use std::time::Duration;
+
+use super::{HttpError, Method, Response};
+use crate::backoff::Backoff;
+
+/// Retry policy, see `RequestBuilder::retry`.
+///
+/// Delays grow from `initial_delay` by `multiplier` after every failed
+/// attempt, up to `max_delay`. A `Retry-After` header takes precedence,
+/// unless it asks for more than `max_delay`: the response is returned then.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Including the first one.
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f32,
+    /// Fraction of each delay that gets randomized, from 0.0 to 1.0.
+    pub jitter: f32,
+    /// Responses with these statuses are retried.
+    pub statuses: Vec<u16>,
+    /// Retry when the server could not be reached or the connection broke.
+    pub network_errors: bool,
+    pub timeouts: bool,
+    /// Retry `POST` and `PATCH` requests too, which might not be safe to repeat.
+    pub non_idempotent: bool,
+}
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.,
+            jitter: 0.5,
+            statuses: vec![408, 429, 500, 502, 503, 504],
+            network_errors: true,
+            timeouts: true,
+            non_idempotent: false,
+        }
+    }
+}
+
+impl Retry {
+    pub(super) fn backoff(&self) -> Backoff {
+        Backoff::new(
+            self.initial_delay,
+            self.max_delay,
+            self.multiplier,
+            self.jitter,
+        )
+    }
+
+    pub(super) fn applies(&self, method: Method) -> bool {
+        self.non_idempotent || !matches!(method, Method::Post | Method::Patch)
+    }
+
+    /// How long to wait before the next attempt, `None` to give up
+    /// and return `result`.
+    pub(super) fn delay(
+        &self,
+        backoff: &mut Backoff,
+        result: &Result<Response, HttpError>,
+    ) -> Option<Duration> {
+        // The backoff counts the retries, the first attempt is not one.
+        if backoff.attempt() + 1 >= self.max_attempts {
+            return None;
+        }
+
+        let retry_after = match result {
+            Ok(response) if self.statuses.contains(&response.status) => response
+                .header("Retry-After")
+                .and_then(|value| retry_after(value, unix_now())),
+            Err(HttpError::TimedOut) if self.timeouts => None,
+            Err(error) if self.network_errors && is_network_error(error) => None,
+            _ => return None,
+        };
+        let delay = backoff.next_delay();
+
+        match retry_after {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(delay),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_network_error(error: &HttpError) -> bool {
+    use ureq::ErrorKind;
+
+    match error {
+        HttpError::NetworkError | HttpError::IOError => true,
+        HttpError::UreqError(error) => matches!(
+            error.kind(),
+            ErrorKind::Dns | ErrorKind::ConnectionFailed | ErrorKind::Io | ErrorKind::ProxyConnect
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn is_network_error(error: &HttpError) -> bool {
+    matches!(error, HttpError::NetworkError)
+}
+
+/// Seconds since the unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or(0.)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn unix_now() -> f64 {
+    crate::backoff::now()
+}
+
+/// Either a number of seconds, or a date like `Wed, 21 Oct 2015 07:28:00 GMT`.
+fn retry_after(value: &str, now: f64) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let mut parts = value.split_whitespace().skip(1);
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|part| part.parse::<i64>());
+    let (hours, minutes, seconds) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    // Leap seconds included, the year keeps the arithmetic below in range.
+    if !(1..=31).contains(&day)
+        || !(0..=9999).contains(&year)
+        || !(0..24).contains(&hours)
+        || !(0..60).contains(&minutes)
+        || !(0..=60).contains(&seconds)
+    {
+        return None;
+    }
+
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+
+    // Days since the epoch of a proleptic gregorian date, from
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let date = days
+        .checked_mul(86400)?
+        .checked_add(hours * 3600 + minutes * 60 + seconds)? as f64;
+
+    // A date in the past means right away.
+    Some(Duration::from_secs_f64((date - now).max(0.)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Sun, 06 Nov 1994 08:49:37 GMT`, the example date of RFC 7231.
+    const RFC_DATE: f64 = 784111777.;
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(retry_after("120", 0.), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(" 0 ", 0.), Some(Duration::from_secs(0)));
+        assert_eq!(
+            retry_after("18446744073709551615", 0.),
+            Some(Duration::from_secs(u64::MAX))
+        );
+        assert_eq!(retry_after("-1", 0.), None);
+    }
+
+    #[test]
+    fn retry_after_date() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        assert_eq!(
+            retry_after(date, RFC_DATE - 30.),
+            Some(Duration::from_secs(30))
+        );
+        // In the past.
+        assert_eq!(
+            retry_after(date, RFC_DATE + 30.),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            retry_after("Thu, 01 Jan 1970 00:00:00 GMT", 0.),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(
+            retry_after("Fri, 31 Dec 9999 23:59:60 GMT", 0.),
+            Some(Duration::from_secs(253402300800))
+        );
+    }
+
+    #[test]
+    fn retry_after_garbage() {
+        for value in [
+            "",
+            "soon",
+            "1.5",
+            "Sun, 06 Nov 1994",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun, 06 Nov -9223372036854775808 08:49:37 GMT",
+            "Sun, 9223372036854775807 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 9223372036854775807:00:00 GMT",
+        ] {
+            assert_eq!(retry_after(value, RFC_DATE), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn retry_after_capped() {
+        let policy = Retry::default();
+        let response = |retry_after: &str| {
+            Ok(Response {
+                status: 503,
+                headers: vec![("Retry-After".to_owned(), retry_after.to_owned())],
+                body: vec![],
+            })
+        };
+
+        let mut backoff = policy.backoff();
+        assert_eq!(
+            policy.delay(&mut backoff, &response("2")),
+            Some(Duration::from_secs(2))
+        );
+        let mut backoff = policy.backoff();
+        assert_eq!(
+            policy.delay(&mut backoff, &response("18446744073709551615")),
+            None
+        );
+        let mut backoff = policy.backoff();
+        assert_eq!(
+            policy.delay(&mut backoff, &response("Fri, 31 Dec 9999 23:59:59 GMT")),
+            None
+        );
+        let mut backoff = policy.backoff();
+        let delay = policy.delay(&mut backoff, &response("garbage")).unwrap();
+        assert!(delay <= policy.max_delay);
+    }
+}
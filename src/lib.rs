@@ -3,13 +3,16 @@
 #[cfg(not(target_arch = "wasm32"))]
 extern crate qws as ws;
 
+mod backoff;
 mod error;
 
+pub use error::Error;
+
 pub mod http_request;
 pub mod quad_socket;
 pub mod web_socket;
 
 #[no_mangle]
 pub extern "C" fn quad_net_crate_version() -> u32 {
-	1
+	11
 }
@@ -3,11 +3,18 @@
 //!
 //! Works through TCP on the desktop and through WebSocket on web.
 //! Server will be capable to receive connections with both TCP and WebSocket
-//! and QuadSocket client will automatically use the only web tech available on 
+//! and QuadSocket client will automatically use the only web tech available on
 //! the current platform
 
 pub mod client;
 
+/// Prefix of the WebSocket text message a server with sessions enabled
+/// sends the session token in.
+const WS_SESSION_PREFIX: &str = "quad_session:";
+/// Query parameter of the WebSocket handshake asking for a session,
+/// with the token of the session to resume as its value, if any.
+const WS_SESSION_QUERY: &str = "quad_session";
+
 #[cfg(not(target_arch = "wasm32"))]
 pub mod server;
 
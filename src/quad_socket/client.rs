@@ -1,29 +1,113 @@
 use std::net::ToSocketAddrs;
+use std::time::Duration;
 
 #[cfg(not(target_arch = "wasm32"))]
 mod tcp;
 #[cfg(target_arch = "wasm32")]
-use crate::web_socket::js_web_socket as websocket;
+mod web;
 
 use crate::error::Error;
 
+/// Default limit for a single frame, 1MB, see `QuadSocket::connect_with_max_frame_size`.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    /// The connection was closed, either by the peer or by the server.
+    Closed {
+        reason: String,
+    },
+    /// The connection could not be established or broke with an error.
+    Failed(Error),
+    /// The connection was lost, waiting before reconnect attempt number `attempt`.
+    Reconnecting {
+        attempt: u32,
+    },
+}
+
+/// State changes of a `QuadSocket`, in the order they happened.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Connected,
+    Closed {
+        reason: String,
+    },
+    Failed(Error),
+    /// Reconnect attempt number `attempt` will be made after `delay`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+}
+
+/// Reconnection policy, see `QuadSocket::connect_with_reconnect`.
+///
+/// Delays grow from `initial_delay` by `multiplier` after every failed
+/// attempt, up to `max_delay`.
+#[derive(Debug, Clone)]
+pub struct Reconnect {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f32,
+    /// Fraction of each delay that gets randomized, from 0.0 to 1.0.
+    pub jitter: f32,
+    /// Give up after this many failed attempts in a row, `None` retries forever.
+    pub max_attempts: Option<u32>,
+    /// Messages sent while reconnecting are queued, up to this amount, and
+    /// sent once the connection is back. 0 disables the queue.
+    pub queue_limit: usize,
+}
+
+impl Default for Reconnect {
+    fn default() -> Reconnect {
+        Reconnect {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.,
+            jitter: 0.5,
+            max_attempts: None,
+            queue_limit: 64,
+        }
+    }
+}
+
+impl Reconnect {
+    pub(crate) fn backoff(&self) -> crate::backoff::Backoff {
+        crate::backoff::Backoff::new(
+            self.initial_delay,
+            self.max_delay,
+            self.multiplier,
+            self.jitter,
+        )
+    }
+
+    pub(crate) fn should_retry(&self, attempt: u32) -> bool {
+        match self.max_attempts {
+            Some(max) => attempt < max,
+            None => true,
+        }
+    }
+}
+
 pub struct QuadSocket {
     #[cfg(not(target_arch = "wasm32"))]
     tcp_socket: tcp::TcpSocket,
     #[cfg(target_arch = "wasm32")]
-    web_socket: websocket::WebSocket,
+    web_socket: web::WebSocket,
 }
 
 impl QuadSocket {
-    pub fn send(&mut self, data: &[u8]) {
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
         #[cfg(not(target_arch = "wasm32"))]
         {
-            self.tcp_socket.send(data);
+            self.tcp_socket.send(data)
         }
 
         #[cfg(target_arch = "wasm32")]
         {
-            self.web_socket.send_bytes(data);
+            self.web_socket.send_bytes(data)
         }
     }
 
@@ -40,34 +124,94 @@ impl QuadSocket {
     }
 }
 
+/// A received message could not be deserialized.
+#[cfg(feature = "nanoserde")]
+pub type DecodeError = nanoserde::DeBinErr;
+
 #[cfg(feature = "nanoserde")]
 impl QuadSocket {
-    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) {
+    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) -> Result<(), Error> {
         use nanoserde::SerBin;
 
-        self.send(&SerBin::serialize_bin(data));
+        self.send(&SerBin::serialize_bin(data))
     }
 
-    pub fn try_recv_bin<T: nanoserde::DeBin + std::fmt::Debug>(&mut self) -> Option<T> {
-        let bytes = self.try_recv()?;
-        let data: T = nanoserde::DeBin::deserialize_bin(&bytes).expect("Cant parse message");
-
-        Some(data)
+    /// Receive and deserialize the next message.
+    ///
+    /// A malformed message is consumed and reported as an error,
+    /// following messages can still be received.
+    pub fn try_recv_bin<T: nanoserde::DeBin>(&mut self) -> Result<Option<T>, DecodeError> {
+        match self.try_recv() {
+            Some(bytes) => nanoserde::DeBin::deserialize_bin(&bytes).map(Some),
+            None => Ok(None),
+        }
     }
 }
 
 impl QuadSocket {
+    pub fn state(&self) -> ConnectionState {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.tcp_socket.state()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.web_socket.state()
+        }
+    }
+
+    /// Pop the next connection event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.tcp_socket.poll_event()
+        }
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            self.web_socket.poll_event()
+        }
+    }
+
     #[cfg(target_arch = "wasm32")]
     pub fn is_wasm_websocket_connected(&self) -> bool {
         self.web_socket.connected()
     }
 
     pub fn connect<A: ToSocketAddrs + std::fmt::Display>(addr: A) -> Result<QuadSocket, Error> {
+        QuadSocket::connect_with_max_frame_size(addr, DEFAULT_MAX_FRAME_SIZE, None)
+    }
+
+    /// Like `connect`, but the connection is re-established according to
+    /// `reconnect` whenever it gets lost.
+    ///
+    /// The first connection attempt is not retried.
+    pub fn connect_with_reconnect<A: ToSocketAddrs + std::fmt::Display>(
+        addr: A,
+        reconnect: Reconnect,
+    ) -> Result<QuadSocket, Error> {
+        QuadSocket::connect_with_max_frame_size(addr, DEFAULT_MAX_FRAME_SIZE, Some(reconnect))
+    }
+
+    /// Like `connect_with_reconnect`, with a limit on incoming and outgoing
+    /// frames other than `DEFAULT_MAX_FRAME_SIZE`. It should match the
+    /// server's `Settings::max_frame_size`.
+    ///
+    /// Browsers have limits of their own, `max_frame_size` is ignored on the web.
+    pub fn connect_with_max_frame_size<A: ToSocketAddrs + std::fmt::Display>(
+        addr: A,
+        max_frame_size: usize,
+        reconnect: Option<Reconnect>,
+    ) -> Result<QuadSocket, Error> {
+        #[cfg(target_arch = "wasm32")]
+        let _ = max_frame_size;
+
         Ok(QuadSocket {
             #[cfg(not(target_arch = "wasm32"))]
-            tcp_socket: tcp::TcpSocket::connect(addr)?,
+            tcp_socket: tcp::TcpSocket::connect(addr, max_frame_size, reconnect)?,
             #[cfg(target_arch = "wasm32")]
-            web_socket: websocket::WebSocket::connect(addr)?,
+            web_socket: web::WebSocket::connect(addr, reconnect)?,
         })
     }
 }
@@ -1,47 +1,266 @@
-use std::net::ToSocketAddrs;
+use std::collections::VecDeque;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 
-use std::net::TcpStream;
-use std::sync::mpsc::{self, Receiver};
+use super::{ConnectionState, Event, Reconnect};
+use crate::{
+    error::Error,
+    quad_socket::protocol::{self, Control, Frame, FrameDecoder},
+};
 
-use crate::{error::Error, quad_socket::protocol::MessageReader};
+/// State shared between the socket and its reader thread.
+struct Shared {
+    /// `None` while the connection is down.
+    writer: Mutex<Option<TcpStream>>,
+    /// Messages sent while reconnecting.
+    queue: Mutex<VecDeque<Vec<u8>>>,
+    state: Mutex<ConnectionState>,
+    /// Token of the server side session, if the server has sessions enabled.
+    session: Mutex<Option<Vec<u8>>>,
+    dropped: AtomicBool,
+    max_frame_size: usize,
+}
 
 pub struct TcpSocket {
-    stream: TcpStream,
+    shared: Arc<Shared>,
     rx: Receiver<Vec<u8>>,
+    events: Receiver<Event>,
+    queue_limit: usize,
 }
 
 impl TcpSocket {
-    pub fn send(&mut self, data: &[u8]) {
-        use std::io::Write;
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        if let Some(stream) = writer.as_mut() {
+            return protocol::write_frame(stream, data, self.shared.max_frame_size);
+        }
+
+        // The reader thread only touches the queue while holding the writer,
+        // so it can not be flushed under our feet.
+        let reconnecting = matches!(
+            *self.shared.state.lock().unwrap(),
+            ConnectionState::Reconnecting { .. }
+        );
+        let mut queue = self.shared.queue.lock().unwrap();
+        if !reconnecting || queue.len() >= self.queue_limit {
+            return Err(Error::ConnectionClosed);
+        }
+        if data.len() > self.shared.max_frame_size {
+            return Err(Error::FrameTooLarge {
+                size: data.len(),
+                max: self.shared.max_frame_size,
+            });
+        }
+        queue.push_back(data.to_vec());
 
-        self.stream.write(&[data.len() as u8]).unwrap();
-        self.stream.write(data).unwrap();
+        Ok(())
     }
 
     pub fn try_recv(&mut self) -> Option<Vec<u8>> {
         self.rx.try_recv().ok()
     }
+
+    pub fn state(&self) -> ConnectionState {
+        self.shared.state.lock().unwrap().clone()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Drop for TcpSocket {
+    fn drop(&mut self) {
+        // Wake up the reader thread, it will notice the flag and quit.
+        self.shared.dropped.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.shared.writer.lock().unwrap().as_mut() {
+            // Lets the server know there is no session to keep around.
+            if self.shared.session.lock().unwrap().is_some() {
+                let _ = protocol::write_control(&mut *stream, &Control::Goodbye);
+            }
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
 }
 
 impl TcpSocket {
-    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpSocket, Error> {
-        let stream = TcpStream::connect(addr)?;
-        stream.set_nodelay(true).unwrap();
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        max_frame_size: usize,
+        reconnect: Option<Reconnect>,
+    ) -> Result<TcpSocket, Error> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        let mut stream = open(&addrs)?;
+        let reader = stream.try_clone()?;
+        // Sessions are only of use to a socket that reconnects.
+        let session = reconnect.as_ref().map(|_| vec![]);
+        protocol::write_control(&mut stream, &Control::Hello(session))?;
 
         let (tx, rx) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let _ = events_tx.send(Event::Connected);
+
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(Some(stream)),
+            queue: Mutex::new(VecDeque::new()),
+            state: Mutex::new(ConnectionState::Connected),
+            session: Mutex::new(None),
+            dropped: AtomicBool::new(false),
+            max_frame_size,
+        });
+        let queue_limit = reconnect
+            .as_ref()
+            .map_or(0, |reconnect| reconnect.queue_limit);
 
         std::thread::spawn({
-            let mut stream = stream.try_clone().unwrap();
-            move || {
-                let mut messages = MessageReader::new();
-                loop {
-                    if let Ok(Some(message)) = messages.next(&mut stream) {
-                        tx.send(message).unwrap();
+            let shared = shared.clone();
+            move || run(reader, addrs, &shared, reconnect, &tx, &events_tx)
+        });
+
+        Ok(TcpSocket {
+            shared,
+            rx,
+            events,
+            queue_limit,
+        })
+    }
+}
+
+fn open(addrs: &[SocketAddr]) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect(addrs)?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+/// Reader thread: forwards messages and, if allowed, brings the connection
+/// back after it gets lost.
+fn run(
+    mut reader: TcpStream,
+    addrs: Vec<SocketAddr>,
+    shared: &Shared,
+    reconnect: Option<Reconnect>,
+    tx: &Sender<Vec<u8>>,
+    events: &Sender<Event>,
+) {
+    let mut backoff = reconnect.as_ref().map(Reconnect::backoff);
+
+    loop {
+        let result = read_frames(reader, shared, tx);
+        if shared.dropped.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let (state, event) = match result {
+            Ok(()) => {
+                let reason = "Connection closed by peer".to_owned();
+                (
+                    ConnectionState::Closed {
+                        reason: reason.clone(),
+                    },
+                    Event::Closed { reason },
+                )
+            }
+            Err(err) => (ConnectionState::Failed(err.clone()), Event::Failed(err)),
+        };
+        {
+            let mut writer = shared.writer.lock().unwrap();
+            *writer = None;
+            *shared.state.lock().unwrap() = state;
+        }
+        let _ = events.send(event);
+
+        let (reconnect, backoff) = match (&reconnect, &mut backoff) {
+            (Some(reconnect), Some(backoff)) => (reconnect, backoff),
+            _ => return,
+        };
+
+        reader = loop {
+            if !reconnect.should_retry(backoff.attempt()) {
+                shared.queue.lock().unwrap().clear();
+                return;
+            }
+            let delay = backoff.next_delay();
+            let attempt = backoff.attempt();
+            *shared.state.lock().unwrap() = ConnectionState::Reconnecting { attempt };
+            let _ = events.send(Event::Reconnecting { attempt, delay });
+
+            std::thread::sleep(delay);
+            if shared.dropped.load(Ordering::SeqCst) {
+                return;
+            }
+
+            match resume(&addrs, shared) {
+                Ok(resumed) => break resumed,
+                Err(_) if shared.dropped.load(Ordering::SeqCst) => return,
+                Err(err) => {
+                    if !reconnect.should_retry(backoff.attempt()) {
+                        shared.queue.lock().unwrap().clear();
+                        *shared.state.lock().unwrap() = ConnectionState::Failed(err.clone());
+                        let _ = events.send(Event::Failed(err));
+                        return;
                     }
                 }
             }
-        });
+        };
+
+        backoff.reset();
+        let _ = events.send(Event::Connected);
+    }
+}
 
-        Ok(TcpSocket { stream, rx })
+/// Open a new connection, ask the server for the old session and flush
+/// the messages queued in the meantime.
+/// Returns a handle for the reader.
+fn resume(addrs: &[SocketAddr], shared: &Shared) -> Result<TcpStream, Error> {
+    let mut stream = open(addrs)?;
+    let reader = stream.try_clone()?;
+
+    let mut writer = shared.writer.lock().unwrap();
+    if shared.dropped.load(Ordering::SeqCst) {
+        return Err(Error::ConnectionClosed);
+    }
+    // A new session if the server did not hand one out yet.
+    let session = shared.session.lock().unwrap().clone().unwrap_or_default();
+    protocol::write_control(&mut stream, &Control::Hello(Some(session)))?;
+    let mut queue = shared.queue.lock().unwrap();
+    while let Some(message) = queue.front() {
+        protocol::write_frame(&mut stream, message, shared.max_frame_size)?;
+        queue.pop_front();
+    }
+    *writer = Some(stream);
+    *shared.state.lock().unwrap() = ConnectionState::Connected;
+
+    Ok(reader)
+}
+
+/// Forward incoming messages until the stream gets closed or breaks.
+fn read_frames(
+    mut stream: TcpStream,
+    shared: &Shared,
+    tx: &mpsc::Sender<Vec<u8>>,
+) -> Result<(), Error> {
+    let mut decoder = FrameDecoder::new(shared.max_frame_size);
+
+    loop {
+        if decoder.read_from(&mut stream)? == 0 {
+            return Ok(());
+        }
+        while let Some(frame) = decoder.next_frame()? {
+            match frame {
+                Frame::Message(message) => {
+                    if tx.send(message).is_err() {
+                        // The socket was dropped, nobody is listening anymore.
+                        return Ok(());
+                    }
+                }
+                Frame::Control(Control::Session(token)) => {
+                    *shared.session.lock().unwrap() = Some(token);
+                }
+                Frame::Control(_) => {}
+            }
+        }
     }
 }
@@ -0,0 +1,194 @@
+//! Reconnection on top of the browser WebSocket.
+//!
+//! There are no threads on the web, so all the work happens in `update`,
+//! called from every `QuadSocket` method.
+//!
+//! When reconnecting is enabled, the socket asks for a session with a
+//! `quad_session` query parameter. A server with sessions enabled then sends
+//! a `quad_session:<token>` text message, the token is given back in the
+//! query string of the reconnection url.
+
+use std::collections::VecDeque;
+use std::net::ToSocketAddrs;
+
+use super::{ConnectionState, Event, Reconnect};
+use crate::{
+    backoff,
+    backoff::Backoff,
+    error::Error,
+    quad_socket::{WS_SESSION_PREFIX, WS_SESSION_QUERY},
+    web_socket::{self, js_web_socket, Message},
+};
+
+pub struct WebSocket {
+    socket: js_web_socket::WebSocket,
+    addr: String,
+    reconnect: Option<(Reconnect, Backoff)>,
+    /// Overrides the browser socket state while reconnecting.
+    reconnecting: Option<u32>,
+    /// When to make the next attempt, in `backoff::now()` time.
+    next_attempt: Option<f64>,
+    queue: VecDeque<Vec<u8>>,
+    /// Left unread in a socket replaced by a reconnection.
+    received: VecDeque<Message>,
+    events: VecDeque<Event>,
+    /// Token of the server side session, if the server has sessions enabled.
+    session: Option<String>,
+}
+
+impl WebSocket {
+    pub fn connect<A: ToSocketAddrs + std::fmt::Display>(
+        addr: A,
+        reconnect: Option<Reconnect>,
+    ) -> Result<WebSocket, Error> {
+        let addr = format!("{}", addr);
+        let url = if reconnect.is_some() {
+            session_url(&addr, None)
+        } else {
+            addr.clone()
+        };
+
+        Ok(WebSocket {
+            socket: js_web_socket::WebSocket::connect(url)?,
+            addr,
+            reconnect: reconnect.map(|reconnect| {
+                let backoff = reconnect.backoff();
+                (reconnect, backoff)
+            }),
+            reconnecting: None,
+            next_attempt: None,
+            queue: VecDeque::new(),
+            received: VecDeque::new(),
+            events: VecDeque::new(),
+            session: None,
+        })
+    }
+
+    pub fn send_bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.update();
+
+        if self.reconnecting.is_none() {
+            return self.socket.send_bytes(data);
+        }
+        match &self.reconnect {
+            Some((reconnect, _)) if self.queue.len() < reconnect.queue_limit => {
+                self.queue.push_back(data.to_vec());
+                Ok(())
+            }
+            _ => Err(Error::ConnectionClosed),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        self.update();
+
+        while let Some(message) = self.received.pop_front().or_else(|| self.socket.try_recv()) {
+            if let Message::Text(text) = &message {
+                if let Some(token) = text.strip_prefix(WS_SESSION_PREFIX) {
+                    self.session = Some(token.to_owned());
+                    continue;
+                }
+            }
+            return Some(message.into_bytes());
+        }
+        None
+    }
+
+    pub fn connected(&self) -> bool {
+        self.reconnecting.is_none() && self.socket.connected()
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        match self.reconnecting {
+            Some(attempt) => ConnectionState::Reconnecting { attempt },
+            None => self.socket.state(),
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.update();
+
+        self.events.pop_front()
+    }
+
+    fn update(&mut self) {
+        while let Some(event) = self.socket.poll_connection_event() {
+            match event {
+                web_socket::Event::Open => {
+                    if self.reconnecting.take().is_some() {
+                        while let Some(message) = self.queue.pop_front() {
+                            let _ = self.socket.send_bytes(&message);
+                        }
+                    }
+                    if let Some((_, backoff)) = &mut self.reconnect {
+                        backoff.reset();
+                    }
+                    self.events.push_back(Event::Connected);
+                }
+                web_socket::Event::Close { code, reason } => {
+                    let reason = if reason.is_empty() {
+                        format!("Closed with code {}", code)
+                    } else {
+                        reason
+                    };
+                    self.disconnected(Event::Closed { reason })
+                }
+                web_socket::Event::Error(error) => self.disconnected(Event::Failed(error)),
+                web_socket::Event::Message(_) => {}
+            }
+        }
+
+        if let Some(time) = self.next_attempt {
+            if backoff::now() >= time {
+                self.next_attempt = None;
+                let url = session_url(&self.addr, self.session.as_deref());
+                // Browsers report failures asynchronously, through events.
+                if let Ok(socket) = js_web_socket::WebSocket::connect(url) {
+                    let mut old = std::mem::replace(&mut self.socket, socket);
+                    while let Some(message) = old.try_recv() {
+                        self.received.push_back(message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Schedule the next attempt, or give up.
+    fn disconnected(&mut self, event: Event) {
+        let (reconnect, backoff) = match &mut self.reconnect {
+            Some(reconnect) => reconnect,
+            None => {
+                self.events.push_back(event);
+                return;
+            }
+        };
+
+        // Only the first disconnect is reported, failed attempts are not
+        // unless they are the last one.
+        let first = self.reconnecting.is_none();
+        if !reconnect.should_retry(backoff.attempt()) {
+            self.reconnecting = None;
+            self.queue.clear();
+            self.events.push_back(event);
+            return;
+        }
+        if first {
+            self.events.push_back(event);
+        }
+
+        let delay = backoff.next_delay();
+        let attempt = backoff.attempt();
+        self.reconnecting = Some(attempt);
+        self.next_attempt = Some(backoff::now() + delay.as_secs_f64());
+        self.events.push_back(Event::Reconnecting { attempt, delay });
+    }
+}
+
+/// `addr` asking for a new session, or for the one with `token`.
+fn session_url(addr: &str, token: Option<&str>) -> String {
+    let separator = if addr.contains('?') { '&' } else { '?' };
+    match token {
+        Some(token) => format!("{}{}{}={}", addr, separator, WS_SESSION_QUERY, token),
+        None => format!("{}{}{}", addr, separator, WS_SESSION_QUERY),
+    }
+}
@@ -1,37 +1,348 @@
+//! TCP framing used by quad_socket.
+//!
+//! Each message is prefixed with its length as a little-endian `u32`.
+//! The highest bit of the length marks control frames, used for sessions.
+//! Clients introduce themselves with one before sending anything else.
+
 use std::io::ErrorKind;
 
+use crate::error::Error;
+
+/// Size of the length prefix in front of every frame.
+pub const HEADER_SIZE: usize = 4;
+
+const CONTROL_BIT: u32 = 1 << 31;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// Client to server, first frame of every connection. `None` when the
+    /// client does not want a session, otherwise the token of the session
+    /// to resume, or empty.
+    Hello(Option<Vec<u8>>),
+    /// Server to client, the token to resume this session with.
+    Session(Vec<u8>),
+    /// Client to server, the client is leaving for good.
+    Goodbye,
+}
+
 #[derive(Debug)]
-pub enum MessageReader {
-    Empty,
-    Amount(usize),
+pub enum Frame {
+    Message(Vec<u8>),
+    Control(Control),
+}
+
+impl Control {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Control::Hello(None) => vec![1, 0],
+            Control::Hello(Some(token)) => [&[1, 1], &token[..]].concat(),
+            Control::Session(token) => [&[2], &token[..]].concat(),
+            Control::Goodbye => vec![3],
+        }
+    }
+
+    fn decode(data: &[u8]) -> Result<Control, Error> {
+        match data.split_first() {
+            Some((1, [0])) => Ok(Control::Hello(None)),
+            Some((1, [1, token @ ..])) => Ok(Control::Hello(Some(token.to_vec()))),
+            Some((2, token)) => Ok(Control::Session(token.to_vec())),
+            Some((3, _)) => Ok(Control::Goodbye),
+            _ => Err(std::io::Error::new(ErrorKind::InvalidData, "Unknown control frame").into()),
+        }
+    }
 }
 
-impl MessageReader {
-    pub fn new() -> MessageReader {
-        MessageReader::Empty
+pub fn write_frame(
+    mut stream: impl std::io::Write,
+    data: &[u8],
+    max_frame_size: usize,
+) -> Result<(), Error> {
+    if data.len() > max_frame_size || data.len() >= CONTROL_BIT as usize {
+        return Err(Error::FrameTooLarge {
+            size: data.len(),
+            max: max_frame_size,
+        });
     }
 
-    pub fn next(&mut self, mut stream: impl std::io::Read) -> Result<Option<Vec<u8>>, ()> {
-        let mut bytes = [0 as u8; 255];
+    stream.write_all(&(data.len() as u32).to_le_bytes())?;
+    stream.write_all(data)?;
 
-        match self {
-            MessageReader::Empty => match stream.read_exact(&mut bytes[0..1]) {
-                Ok(_) => {
-                    *self = MessageReader::Amount(bytes[0] as usize);
-                    Ok(None)
+    Ok(())
+}
+
+pub fn write_control(mut stream: impl std::io::Write, control: &Control) -> Result<(), Error> {
+    let data = control.encode();
+
+    stream.write_all(&(data.len() as u32 | CONTROL_BIT).to_le_bytes())?;
+    stream.write_all(&data)?;
+
+    Ok(())
+}
+
+/// Incremental frame decoder.
+///
+/// Bytes are accumulated across reads, so a read interrupted by `WouldBlock`
+/// halfway through a frame never loses data.
+///
+/// At most one maximum sized frame and its header are buffered, frames have
+/// to be taken out with `next_frame` before reading more.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Start of the first frame not returned by `next_frame` yet.
+    /// Consumed bytes are only removed on the next read.
+    consumed: usize,
+    max_frame_size: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(max_frame_size: usize) -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::with_capacity(4096),
+            consumed: 0,
+            max_frame_size,
+        }
+    }
+
+    /// True when nothing more can be read until frames are taken out.
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() - self.consumed >= HEADER_SIZE + self.max_frame_size
+    }
+
+    /// Performs a single read from the stream into the internal buffer.
+    /// Returns the amount of bytes read, `Ok(0)` means the peer closed the stream.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` when the decoder `is_full`.
+    pub fn read_from(&mut self, mut stream: impl std::io::Read) -> std::io::Result<usize> {
+        let mut chunk = [0; 4096];
+
+        if self.consumed != 0 {
+            self.buffer.drain(0..self.consumed);
+            self.consumed = 0;
+        }
+        let room = (HEADER_SIZE + self.max_frame_size).saturating_sub(self.buffer.len());
+        if room == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Frame decoder is full",
+            ));
+        }
+        let len = room.min(chunk.len());
+
+        loop {
+            match stream.read(&mut chunk[0..len]) {
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[0..n]);
+                    return Ok(n);
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                Err(_err) => Err(()),
-            },
-            MessageReader::Amount(len) => match stream.read_exact(&mut bytes[0..*len]) {
-                Ok(_) => {
-                    let msg = bytes[0..*len].to_vec();
-                    *self = MessageReader::Empty;
-                    Ok(Some(msg))
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Pops the next complete frame from the buffer, if any.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let buffer = &self.buffer[self.consumed..];
+        if buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&buffer[0..HEADER_SIZE]);
+        let header = u32::from_le_bytes(header);
+        let control = header & CONTROL_BIT != 0;
+        let len = (header & !CONTROL_BIT) as usize;
+        if len > self.max_frame_size {
+            return Err(Error::FrameTooLarge {
+                size: len,
+                max: self.max_frame_size,
+            });
+        }
+
+        if buffer.len() < HEADER_SIZE + len {
+            return Ok(None);
+        }
+
+        let data = buffer[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+        self.consumed += HEADER_SIZE + len;
+
+        if control {
+            Ok(Some(Frame::Control(Control::decode(&data)?)))
+        } else {
+            Ok(Some(Frame::Message(data)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::Read;
+
+    /// Hands out the scripted reads one by one, `None` stands for `WouldBlock`.
+    struct Script(VecDeque<Option<Vec<u8>>>);
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(mut data)) => {
+                    let n = data.len().min(buf.len());
+                    buf[0..n].copy_from_slice(&data[0..n]);
+                    if n < data.len() {
+                        self.0.push_front(Some(data.split_off(n)));
+                    }
+                    Ok(n)
+                }
+                Some(None) => Err(ErrorKind::WouldBlock.into()),
+                None => Ok(0),
+            }
+        }
+    }
+
+    const MAX_FRAME_SIZE: usize = 1024;
+
+    fn frame(data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        write_frame(&mut frame, data, MAX_FRAME_SIZE).unwrap();
+        frame
+    }
+
+    /// Reads until EOF, collecting the frames decoded after every read.
+    fn decode(decoder: &mut FrameDecoder, mut stream: Script) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+        loop {
+            match decoder.read_from(&mut stream) {
+                Ok(0) => return messages,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{}", err),
+            }
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                match frame {
+                    Frame::Message(data) => messages.push(data),
+                    Frame::Control(control) => panic!("unexpected {:?}", control),
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
-                Err(_) => Err(()),
-            },
+            }
         }
     }
+
+    #[test]
+    fn partial_chunks() {
+        let bytes = [frame(b"hello"), frame(b""), frame(b"world")].concat();
+        let chunks = bytes.chunks(3).map(|chunk| Some(chunk.to_vec())).collect();
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        let messages = decode(&mut decoder, Script(chunks));
+
+        assert_eq!(messages, vec![b"hello".to_vec(), vec![], b"world".to_vec()]);
+    }
+
+    #[test]
+    fn would_block_mid_header_and_body() {
+        let bytes = [frame(b"first"), frame(b"second")].concat();
+        let script = vec![
+            Some(bytes[0..2].to_vec()),
+            None,
+            Some(bytes[2..6].to_vec()),
+            None,
+            Some(bytes[6..11].to_vec()),
+            None,
+            None,
+            Some(bytes[11..].to_vec()),
+        ];
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        let messages = decode(&mut decoder, Script(script.into()));
+
+        assert_eq!(messages, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn many_small_frames() {
+        let bytes: Vec<u8> = (0..1000u32).flat_map(|i| frame(&i.to_le_bytes())).collect();
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        let messages = decode(&mut decoder, Script(vec![Some(bytes)].into()));
+
+        assert_eq!(messages.len(), 1000);
+        assert_eq!(messages[999], 999u32.to_le_bytes());
+    }
+
+    #[test]
+    fn oversize_frame() {
+        let header = ((MAX_FRAME_SIZE + 1) as u32).to_le_bytes().to_vec();
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        decoder
+            .read_from(Script(vec![Some(header)].into()))
+            .unwrap();
+
+        match decoder.next_frame() {
+            Err(Error::FrameTooLarge { size, max }) => {
+                assert_eq!((size, max), (MAX_FRAME_SIZE + 1, MAX_FRAME_SIZE))
+            }
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+        assert!(write_frame(vec![], &[0; MAX_FRAME_SIZE + 1], MAX_FRAME_SIZE).is_err());
+    }
+
+    #[test]
+    fn full_decoder_stops_reading() {
+        let bytes = [frame(&[1; MAX_FRAME_SIZE]), frame(b"next")].concat();
+        let mut stream = Script(vec![Some(bytes)].into());
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        while !decoder.is_full() {
+            decoder.read_from(&mut stream).unwrap();
+        }
+        let err = decoder.read_from(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        match decoder.next_frame().unwrap() {
+            Some(Frame::Message(data)) => assert_eq!(data, vec![1; MAX_FRAME_SIZE]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(decode(&mut decoder, stream), vec![b"next".to_vec()]);
+    }
+
+    #[test]
+    fn control_frames() {
+        let controls = [
+            Control::Hello(None),
+            Control::Hello(Some(vec![])),
+            Control::Hello(Some(vec![1, 2, 3])),
+            Control::Session(vec![4; 16]),
+            Control::Goodbye,
+        ];
+        let mut bytes = vec![];
+        for control in &controls {
+            write_control(&mut bytes, control).unwrap();
+        }
+        bytes.extend(frame(b"message"));
+
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        decoder.read_from(Script(vec![Some(bytes)].into())).unwrap();
+
+        for control in &controls {
+            match decoder.next_frame().unwrap() {
+                Some(Frame::Control(decoded)) => assert_eq!(&decoded, control),
+                other => panic!("expected {:?}, got {:?}", control, other),
+            }
+        }
+        match decoder.next_frame().unwrap() {
+            Some(Frame::Message(data)) => assert_eq!(data, b"message"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(decoder.next_frame().unwrap().is_none());
+
+        let mut unknown = vec![];
+        unknown.extend_from_slice(&(1 | CONTROL_BIT).to_le_bytes());
+        unknown.push(42);
+        decoder
+            .read_from(Script(vec![Some(unknown)].into()))
+            .unwrap();
+        assert!(decoder.next_frame().is_err());
+    }
 }
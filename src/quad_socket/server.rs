@@ -1,224 +1,692 @@
 use std::net::ToSocketAddrs;
-use std::net::{TcpListener, TcpStream};
-use std::time::{Duration, Instant};
+use std::net::{SocketAddr, TcpListener};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use std::sync::{Arc, Mutex};
 
-use super::protocol::MessageReader;
+use crate::error::Error;
 
-pub struct Settings<F, F1, F2, S>
+mod registry;
+mod session;
+mod tcp;
+mod web_socket;
+
+pub use registry::ConnectionId;
+use registry::Registry;
+
+pub struct Settings<F, F1, F2, F3, F4, F5, S>
 where
     F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
     F1: Fn(&mut SocketHandle, &S) + Send + 'static,
     F2: Fn(&S) + Send + 'static,
-    S: Default + Send,
+    F3: Fn(&mut SocketHandle, &ConnectionInfo) -> Option<S> + Send + 'static,
+    F4: Fn(&mut SocketHandle, &mut S) + Send + 'static,
+    F5: Fn(&mut SocketHandle, &mut S, &ConnectionInfo) + Send + 'static,
+    S: Send,
 {
+    /// Called for each new client, before any other callback.
+    /// Returns the per-connection state, or `None` to reject the client.
+    pub on_connect: F3,
     pub on_message: F,
     pub on_timer: F1,
     pub on_disconnect: F2,
+    /// Called instead of `on_disconnect` when the connection of a client with
+    /// a session is lost. The state is kept for `session_grace`.
+    pub on_suspend: F4,
+    /// Called when a suspended client comes back, with the new connection.
+    /// The client gets its old `ConnectionId` back.
+    pub on_resume: F5,
     pub timer: Option<Duration>,
+    /// Largest TCP frame accepted from a client, in bytes.
+    /// Clients sending anything bigger get disconnected.
+    pub max_frame_size: usize,
+    /// How long the state of a client that lost its connection is kept
+    /// for it to reconnect, `None` disables sessions.
+    ///
+    /// With sessions enabled, TCP clients need to be `QuadSocket`s of this
+    /// version of the crate or newer. Only clients asking for a session get
+    /// one, `QuadSocket` does when it is set up to reconnect. On WebSocket
+    /// that is a `quad_session` query parameter in the handshake path.
+    pub session_grace: Option<Duration>,
 
     pub _marker: std::marker::PhantomData<S>,
 }
 
-enum Sender<'a> {
-    WebSocket(&'a ws::Sender),
-    Tcp(&'a mut TcpStream),
+/// The callbacks of `Settings`, boxed so the server only depends on the state type.
+#[allow(clippy::type_complexity)]
+struct Callbacks<S> {
+    on_connect: Box<dyn Fn(&mut SocketHandle, &ConnectionInfo) -> Option<S> + Send>,
+    on_message: Box<dyn Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send>,
+    on_timer: Box<dyn Fn(&mut SocketHandle, &S) + Send>,
+    on_disconnect: Box<dyn Fn(&S) + Send>,
+    on_suspend: Box<dyn Fn(&mut SocketHandle, &mut S) + Send>,
+    on_resume: Box<dyn Fn(&mut SocketHandle, &mut S, &ConnectionInfo) + Send>,
 }
 
-pub struct SocketHandle<'a> {
-    sender: Sender<'a>,
-    disconnect: bool,
-}
+/// Callbacks shared between the TCP and WebSocket threads.
+type SharedSettings<S> = Arc<Mutex<Callbacks<S>>>;
 
-impl<'a> Sender<'a> {
-    fn send(&mut self, data: &[u8]) -> Option<()> {
-        use std::io::Write;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    WebSocket,
+}
 
-        match self {
-            Sender::WebSocket(out) => {
-                out.send(data).ok()?;
-            }
-            Sender::Tcp(stream) => {
-                stream.write(&[data.len() as u8]).ok()?;
-                stream.write(data).ok()?;
-            }
-        }
+/// Information about a freshly connected client, given to `on_connect`.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer_addr: Option<SocketAddr>,
+    pub transport: Transport,
+    /// Request path of the WebSocket handshake, `None` for TCP.
+    pub path: Option<String>,
+    /// Headers of the WebSocket handshake, empty for TCP.
+    pub headers: Vec<(String, String)>,
+    /// Origin of the WebSocket handshake, if the client sent one.
+    pub origin: Option<String>,
+}
 
-        Some(())
-    }
+/// Gives callbacks access to the connection they are called for,
+/// as well as to every other connected client.
+pub struct SocketHandle<'a> {
+    id: ConnectionId,
+    registry: &'a Registry,
+    disconnect: bool,
 }
 
 impl<'a> SocketHandle<'a> {
-    fn new(sender: Sender<'a>) -> SocketHandle<'a> {
+    fn new(id: ConnectionId, registry: &'a Registry) -> SocketHandle<'a> {
         SocketHandle {
-            sender,
+            id,
+            registry,
             disconnect: false,
         }
     }
 
-    pub fn send(&mut self, data: &[u8]) -> Result<(), ()> {
-        self.sender.send(data).ok_or(())
+    /// Id of the connection this callback is called for.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Ids of all the currently connected clients.
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.registry.connections()
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.registry.send_to(self.id, data)
     }
 
     #[cfg(feature = "nanoserde")]
-    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) -> Result<(), ()> {
+    pub fn send_bin<T: nanoserde::SerBin>(&mut self, data: &T) -> Result<(), Error> {
         self.send(&nanoserde::SerBin::serialize_bin(data))
     }
 
+    /// Send to any connected client, no matter the transport.
+    pub fn send_to(&mut self, id: ConnectionId, data: &[u8]) -> Result<(), Error> {
+        self.registry.send_to(id, data)
+    }
+
+    /// Send to every connected client, including this one.
+    pub fn broadcast(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.registry.broadcast(data, None)
+    }
+
+    /// Send to every connected client but the given one.
+    pub fn broadcast_except(&mut self, id: ConnectionId, data: &[u8]) -> Result<(), Error> {
+        self.registry.broadcast(data, Some(id))
+    }
+
     pub fn disconnect(&mut self) {
         self.disconnect = true;
     }
 }
 
-pub fn listen<A, A1, F, F1, F2, S>(tcp_addr: A, ws_addr: A1, settings: Settings<F, F1, F2, S>)
-where
+/// Handle to a running server, returned by [`Server::start`].
+///
+/// Dropping the handle leaves the server running in the background.
+pub struct ServerHandle {
+    tcp_addr: SocketAddr,
+    ws_addr: SocketAddr,
+    ws_sender: ws::Sender,
+    registry: Arc<Registry>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Address the TCP listener is actually bound to.
+    pub fn tcp_addr(&self) -> SocketAddr {
+        self.tcp_addr
+    }
+
+    /// Address the WebSocket listener is actually bound to.
+    pub fn ws_addr(&self) -> SocketAddr {
+        self.ws_addr
+    }
+
+    /// Ids of all the currently connected clients.
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.registry.connections()
+    }
+
+    pub fn send_to(&self, id: ConnectionId, data: &[u8]) -> Result<(), Error> {
+        self.registry.send_to(id, data)
+    }
+
+    pub fn broadcast(&self, data: &[u8]) -> Result<(), Error> {
+        self.registry.broadcast(data, None)
+    }
+
+    pub fn broadcast_except(&self, id: ConnectionId, data: &[u8]) -> Result<(), Error> {
+        self.registry.broadcast(data, Some(id))
+    }
+
+    /// Disconnect all clients, calling `on_disconnect` for each of them,
+    /// suspended ones included, and wait for the server threads to finish.
+    pub fn shutdown(self) {
+        let _ = self.ws_sender.shutdown();
+        self.registry.command(tcp::Command::Shutdown);
+
+        self.join();
+    }
+
+    /// Block until the server stops.
+    pub fn join(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub struct Server;
+
+impl Server {
+    /// Bind both TCP and WebSocket listeners and serve them on background threads.
+    ///
+    /// Returns as soon as both listeners are bound, so port 0 may be used
+    /// and the real port obtained from the returned handle.
+    pub fn start<A, A1, F, F1, F2, F3, F4, F5, S>(
+        tcp_addr: A,
+        ws_addr: A1,
+        settings: Settings<F, F1, F2, F3, F4, F5, S>,
+    ) -> Result<ServerHandle, Error>
+    where
+        A: ToSocketAddrs,
+        A1: ToSocketAddrs,
+        F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
+        F1: Fn(&mut SocketHandle, &S) + Send + 'static,
+        F2: Fn(&S) + Send + 'static,
+        F3: Fn(&mut SocketHandle, &ConnectionInfo) -> Option<S> + Send + 'static,
+        F4: Fn(&mut SocketHandle, &mut S) + Send + 'static,
+        F5: Fn(&mut SocketHandle, &mut S, &ConnectionInfo) + Send + 'static,
+        S: Send + 'static,
+    {
+        let timer = settings.timer;
+        let max_frame_size = settings.max_frame_size;
+        let sessions = Arc::new(session::Sessions::new(settings.session_grace));
+        let settings = Arc::new(Mutex::new(Callbacks {
+            on_connect: Box::new(settings.on_connect),
+            on_message: Box::new(settings.on_message),
+            on_timer: Box::new(settings.on_timer),
+            on_disconnect: Box::new(settings.on_disconnect),
+            on_suspend: Box::new(settings.on_suspend),
+            on_resume: Box::new(settings.on_resume),
+        }));
+        let (tcp_commands, commands) = mio_extras::channel::channel();
+        let registry = Arc::new(Registry::new(tcp_commands, max_frame_size));
+
+        let ws = web_socket::build(registry.clone(), settings.clone(), sessions.clone(), timer)?
+            .bind(ws_addr)?;
+        let ws_addr = ws.local_addr()?;
+        let ws_sender = ws.broadcaster();
+
+        let listener = TcpListener::bind(tcp_addr)?;
+        let tcp_addr = listener.local_addr()?;
+
+        let threads = vec![
+            std::thread::spawn(move || {
+                let _ = ws.run();
+            }),
+            std::thread::spawn({
+                let context = tcp::Context {
+                    registry: registry.clone(),
+                    settings,
+                    sessions,
+                    timer,
+                    max_frame_size,
+                };
+                move || {
+                    let _ = tcp::run(listener, commands, context);
+                }
+            }),
+        ];
+
+        Ok(ServerHandle {
+            tcp_addr,
+            ws_addr,
+            ws_sender,
+            registry,
+            threads,
+        })
+    }
+}
+
+/// Run the server on the current thread, blocking forever.
+///
+/// Panics if either of the addresses can't be bound, use [`Server::start`]
+/// to handle that.
+pub fn listen<A, A1, F, F1, F2, F3, F4, F5, S>(
+    tcp_addr: A,
+    ws_addr: A1,
+    settings: Settings<F, F1, F2, F3, F4, F5, S>,
+) where
     A: ToSocketAddrs + std::fmt::Debug + Send,
     A1: ToSocketAddrs + std::fmt::Debug + Send + 'static,
     F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
     F1: Fn(&mut SocketHandle, &S) + Send + 'static,
     F2: Fn(&S) + Send + 'static,
-    S: Default + Send + 'static,
+    F3: Fn(&mut SocketHandle, &ConnectionInfo) -> Option<S> + Send + 'static,
+    F4: Fn(&mut SocketHandle, &mut S) + Send + 'static,
+    F5: Fn(&mut SocketHandle, &mut S, &ConnectionInfo) + Send + 'static,
+    S: Send + 'static,
 {
-    let on_message = Arc::new(Mutex::new(settings.on_message));
-    let on_timer = Arc::new(Mutex::new(settings.on_timer));
-    let on_disconnect = Arc::new(Mutex::new(settings.on_disconnect));
-    let timer = settings.timer;
+    Server::start(tcp_addr, ws_addr, settings).unwrap().join();
+}
 
-    struct WsHandler<
-        S: Default,
-        F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
-        F1: Fn(&mut SocketHandle, &S) + Send + 'static,
-        F2: Fn(&S) + Send + 'static,
-    > {
-        out: ws::Sender,
-        state: S,
-        on_message: Arc<Mutex<F>>,
-        on_timer: Arc<Mutex<F1>>,
-        on_disconnect: Arc<Mutex<F2>>,
-        timeout: Option<Duration>,
-    }
-
-    impl<
-            S: Default,
-            F: Fn(&mut SocketHandle, &mut S, Vec<u8>) + Send + 'static,
-            F1: Fn(&mut SocketHandle, &S) + Send + 'static,
-            F2: Fn(&S) + Send + 'static,
-        > ws::Handler for WsHandler<S, F, F1, F2>
-    {
-        fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
-            let data = msg.into_data();
-            let mut handle = SocketHandle::new(Sender::WebSocket(&self.out));
-            (self.on_message.lock().unwrap())(&mut handle, &mut self.state, data);
-            if handle.disconnect {
-                self.out.close(ws::CloseCode::Normal)?;
-            }
-            Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quad_socket::client::{QuadSocket, Reconnect, DEFAULT_MAX_FRAME_SIZE};
+    use crate::quad_socket::protocol::{self, Control, Frame, FrameDecoder};
+    use crate::quad_socket::WS_SESSION_PREFIX;
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Logs every callback, the state of a client is the id it connected with.
+    ///
+    /// Messages are answered with `echo:<message>`, `others:<message>` is
+    /// sent to everybody else and `bye` disconnects.
+    fn start(session_grace: Option<Duration>) -> (ServerHandle, Log) {
+        let log = Log::default();
+        let push = |log: &Log, entry: String| log.lock().unwrap().push(entry);
+
+        let server = Server::start(
+            "127.0.0.1:0",
+            "127.0.0.1:0",
+            Settings {
+                on_connect: {
+                    let log = log.clone();
+                    move |out: &mut SocketHandle, _: &ConnectionInfo| {
+                        push(&log, format!("connect {}", out.id().0));
+                        Some(out.id())
+                    }
+                },
+                on_message: {
+                    let log = log.clone();
+                    move |out: &mut SocketHandle, _: &mut ConnectionId, message: Vec<u8>| {
+                        push(&log, format!("message {}", out.id().0));
+                        if message == b"bye" {
+                            out.disconnect();
+                        } else if let Some(message) = message.strip_prefix(b"others:") {
+                            out.broadcast_except(out.id(), message).unwrap();
+                        } else {
+                            out.send(&[b"echo:", &message[..]].concat()).unwrap();
+                        }
+                    }
+                },
+                on_timer: |_, _| {},
+                on_disconnect: {
+                    let log = log.clone();
+                    move |id: &ConnectionId| push(&log, format!("disconnect {}", id.0))
+                },
+                on_suspend: {
+                    let log = log.clone();
+                    move |_: &mut SocketHandle, id: &mut ConnectionId| {
+                        push(&log, format!("suspend {}", id.0))
+                    }
+                },
+                on_resume: {
+                    let log = log.clone();
+                    move |out: &mut SocketHandle, id: &mut ConnectionId, _: &ConnectionInfo| {
+                        push(&log, format!("resume {} as {}", id.0, out.id().0))
+                    }
+                },
+                timer: None,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                session_grace,
+                _marker: std::marker::PhantomData,
+            },
+        )
+        .unwrap();
+
+        (server, log)
+    }
+
+    fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            std::thread::sleep(Duration::from_millis(5));
         }
+    }
+
+    fn wait_for(log: &Log, entry: &str) {
+        wait_until(entry, || log.lock().unwrap().iter().any(|e| e == entry));
+    }
+
+    fn recv(socket: &mut QuadSocket) -> Vec<u8> {
+        let mut message = None;
+        wait_until("a message", || {
+            message = socket.try_recv();
+            message.is_some()
+        });
+        message.unwrap()
+    }
+
+    /// A client speaking the protocol by hand.
+    struct Raw {
+        stream: TcpStream,
+        decoder: FrameDecoder,
+    }
+
+    impl Raw {
+        fn connect(addr: SocketAddr, session: Option<Vec<u8>>) -> Raw {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            protocol::write_control(&mut stream, &Control::Hello(session)).unwrap();
 
-        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
-            if let Some(timeout) = self.timeout {
-                self.out
-                    .timeout(timeout.as_millis() as _, ws::util::Token(1))?;
+            Raw {
+                stream,
+                decoder: FrameDecoder::new(DEFAULT_MAX_FRAME_SIZE),
             }
-            Ok(())
         }
 
-        fn on_timeout(&mut self, _: ws::util::Token) -> ws::Result<()> {
-            if let Some(timeout) = self.timeout {
-                let mut handle = SocketHandle::new(Sender::WebSocket(&self.out));
-                (self.on_timer.lock().unwrap())(&mut handle, &self.state);
-                if handle.disconnect == false {
-                    self.out
-                        .timeout(timeout.as_millis() as _, ws::util::Token(1))?;
-                } else {
-                    self.out.close(ws::CloseCode::Normal)?;
+        /// The next frame, `None` once the server closed the connection.
+        fn next(&mut self) -> Option<Frame> {
+            loop {
+                if let Some(frame) = self.decoder.next_frame().unwrap() {
+                    return Some(frame);
+                }
+                match self.decoder.read_from(&mut self.stream) {
+                    Ok(0) => return None,
+                    Ok(_) => {}
+                    // Closed with our unread hello still in its buffer.
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => return None,
+                    Err(err) => panic!("{}", err),
                 }
             }
-            Ok(())
         }
 
-        fn on_close(&mut self, _code: ws::CloseCode, _reason: &str) {
-            (self.on_disconnect.lock().unwrap())(&self.state);
+        fn session(&mut self) -> Vec<u8> {
+            loop {
+                match self.next() {
+                    Some(Frame::Control(Control::Session(token))) => return token,
+                    Some(_) => {}
+                    None => panic!("closed before getting a session"),
+                }
+            }
         }
     }
 
-    std::thread::spawn({
-        let on_message = on_message.clone();
-        let on_timer = on_timer.clone();
-        let on_disconnect = on_disconnect.clone();
+    #[test]
+    fn many_clients() {
+        let (server, _) = start(None);
 
-        move || {
-            ws::Builder::new()
-                .with_settings(ws::Settings {
-                    timer_tick_millis: 10,
-                    tcp_nodelay: true,
-                    ..ws::Settings::default()
-                })
-                .build(move |out| {
-                    let on_message = on_message.clone();
-                    let on_timer = on_timer.clone();
-                    let on_disconnect = on_disconnect.clone();
-
-                    WsHandler {
-                        out,
-                        state: S::default(),
-                        on_message,
-                        on_timer,
-                        on_disconnect,
-                        timeout: timer,
+        let clients: Vec<_> = (0..8)
+            .map(|client| {
+                let addr = server.tcp_addr();
+                std::thread::spawn(move || {
+                    let mut socket = QuadSocket::connect(addr).unwrap();
+                    let messages: Vec<Vec<u8>> = (0..100)
+                        .map(|i| format!("{}-{}", client, i).into_bytes())
+                        .chain(Some(vec![client as u8; 256 * 1024]))
+                        .collect();
+                    for message in &messages {
+                        socket.send(message).unwrap();
+                    }
+                    for message in &messages {
+                        assert_eq!(recv(&mut socket), [b"echo:", &message[..]].concat());
                     }
                 })
-                .unwrap()
-                .listen(ws_addr)
-                .unwrap();
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
         }
-    });
 
-    let listener = TcpListener::bind(tcp_addr).unwrap();
-    for stream in listener.incoming() {
-        let on_message = on_message.clone();
-        let on_timer = on_timer.clone();
-        let on_disconnect = on_disconnect.clone();
+        server.shutdown();
+    }
 
-        std::thread::spawn(move || {
-            let mut stream = stream.unwrap();
-            stream.set_nodelay(true).unwrap();
-            stream.set_nonblocking(true).unwrap();
-            let mut message_reader = MessageReader::new();
-            let mut state = S::default();
+    #[test]
+    fn shutdown_disconnects_everybody() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
 
-            let mut time = Instant::now();
-            loop {
-                match message_reader.next(&mut stream) {
-                    Ok(Some(message)) => {
-                        let mut handle = SocketHandle::new(Sender::Tcp(&mut stream));
-                        (on_message.lock().unwrap())(&mut handle, &mut state, message);
-                        if handle.disconnect {
-                            (on_disconnect.lock().unwrap())(&state);
-                            return;
-                        }
-                    }
-                    Ok(None) => {}
-                    Err(_err) => {
-                        (on_disconnect.lock().unwrap())(&state);
-                        return;
-                    }
+        let _connected = QuadSocket::connect(server.tcp_addr()).unwrap();
+        wait_for(&log, "connect 0");
+        let mut suspended = Raw::connect(server.tcp_addr(), Some(vec![]));
+        suspended.session();
+        wait_for(&log, "connect 1");
+        drop(suspended);
+        wait_for(&log, "suspend 1");
+
+        server.shutdown();
+
+        let log = log.lock().unwrap();
+        assert!(log.contains(&"disconnect 0".to_owned()), "{:?}", log);
+        assert!(log.contains(&"disconnect 1".to_owned()), "{:?}", log);
+    }
+
+    #[test]
+    fn broadcast_except_sender() {
+        let (server, log) = start(None);
+
+        let mut sockets: Vec<_> = (0..3)
+            .map(|_| QuadSocket::connect(server.tcp_addr()).unwrap())
+            .collect();
+        wait_until("three clients", || server.connections().len() == 3);
+
+        sockets[0].send(b"others:hello").unwrap();
+        assert_eq!(recv(&mut sockets[1]), b"hello");
+        assert_eq!(recv(&mut sockets[2]), b"hello");
+
+        // Anything the sender got would come before this answer.
+        sockets[0].send(b"ping").unwrap();
+        assert_eq!(recv(&mut sockets[0]), b"echo:ping");
+        assert_eq!(log.lock().unwrap().len(), 5);
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn on_connect_rejects() {
+        let disconnected = Arc::new(Mutex::new(false));
+        let server = Server::start(
+            "127.0.0.1:0",
+            "127.0.0.1:0",
+            Settings {
+                on_connect: |_: &mut SocketHandle, _: &ConnectionInfo| None::<()>,
+                on_message: |_: &mut SocketHandle, _: &mut (), _| {},
+                on_timer: |_, _| {},
+                on_disconnect: {
+                    let disconnected = disconnected.clone();
+                    move |_: &()| *disconnected.lock().unwrap() = true
+                },
+                on_suspend: |_, _| {},
+                on_resume: |_, _, _| {},
+                timer: None,
+                max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                session_grace: None,
+                _marker: std::marker::PhantomData,
+            },
+        )
+        .unwrap();
+
+        let mut raw = Raw::connect(server.tcp_addr(), None);
+        assert!(raw.next().is_none());
+        assert!(server.connections().is_empty());
+
+        server.shutdown();
+        assert!(!*disconnected.lock().unwrap());
+    }
+
+    #[test]
+    fn reconnecting_client_resumes() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
+
+        let reconnect = Reconnect {
+            initial_delay: Duration::from_millis(10),
+            ..Reconnect::default()
+        };
+        let mut socket = QuadSocket::connect_with_reconnect(server.tcp_addr(), reconnect).unwrap();
+        // Right away, the hello has to make it to the server first.
+        socket.send(b"ping").unwrap();
+        assert_eq!(recv(&mut socket), b"echo:ping");
+        wait_for(&log, "connect 0");
+
+        // Drops the connection as if the network went down.
+        server.registry.kick(ConnectionId(0));
+        wait_for(&log, "suspend 0");
+        wait_for(&log, "resume 0 as 0");
+
+        socket.send(b"again").unwrap();
+        assert_eq!(recv(&mut socket), b"echo:again");
+        assert_eq!(server.connections(), vec![ConnectionId(0)]);
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn session_expires() {
+        let (server, log) = start(Some(Duration::from_millis(50)));
+
+        let mut raw = Raw::connect(server.tcp_addr(), Some(vec![]));
+        let token = raw.session();
+        drop(raw);
+        wait_for(&log, "suspend 0");
+        wait_for(&log, "disconnect 0");
+
+        // Too late to resume, a new client.
+        let mut raw = Raw::connect(server.tcp_addr(), Some(token));
+        raw.session();
+        wait_for(&log, "connect 1");
+        assert!(!log.lock().unwrap().iter().any(|e| e.starts_with("resume")));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn goodbye_ends_session() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
+
+        let socket =
+            QuadSocket::connect_with_reconnect(server.tcp_addr(), Reconnect::default()).unwrap();
+        wait_for(&log, "connect 0");
+        // Let the client get its token, it only says goodbye with one.
+        std::thread::sleep(Duration::from_millis(100));
+        drop(socket);
+        wait_for(&log, "disconnect 0");
+        assert!(!log.lock().unwrap().contains(&"suspend 0".to_owned()));
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn no_session_without_reconnect() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
+
+        let socket = QuadSocket::connect(server.tcp_addr()).unwrap();
+        wait_for(&log, "connect 0");
+        server.registry.kick(ConnectionId(0));
+        wait_for(&log, "disconnect 0");
+        assert!(!log.lock().unwrap().contains(&"suspend 0".to_owned()));
+        drop(socket);
+
+        server.shutdown();
+    }
+
+    /// Messages received by a WebSocket client, which runs until the server
+    /// closes the connection or `Sender::close` is called.
+    #[allow(clippy::result_large_err)]
+    fn ws_connect(url: String) -> (ws::Sender, std::sync::mpsc::Receiver<String>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (out_tx, out_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            ws::connect(url, |out| {
+                let _ = out_tx.send(out);
+                let tx = tx.clone();
+                move |message: ws::Message| {
+                    let _ = tx.send(message.to_string());
+                    Ok(())
                 }
+            })
+            .unwrap();
+        });
 
-                if let Some(timer) = timer {
-                    if time.elapsed() >= timer {
-                        time = Instant::now();
-                        let mut handle = SocketHandle::new(Sender::Tcp(&mut stream));
+        (out_rx.recv().unwrap(), rx)
+    }
 
-                        (on_timer.lock().unwrap())(&mut handle, &state);
-                        if handle.disconnect {
-                            (on_disconnect.lock().unwrap())(&state);
-                            return;
-                        }
-                    }
+    #[test]
+    fn web_socket_sessions() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
+        let url = format!("ws://{}/", server.ws_addr());
+        let timeout = Duration::from_secs(5);
+
+        // Not asked for.
+        let (plain, messages) = ws_connect(url.clone());
+        plain.send("ping").unwrap();
+        assert_eq!(messages.recv_timeout(timeout).unwrap(), "echo:ping");
+        plain.close(ws::CloseCode::Normal).unwrap();
+        wait_for(&log, "disconnect 0");
+
+        let (_old, old_messages) = ws_connect(format!("{}?quad_session", url));
+        let message = old_messages.recv_timeout(timeout).unwrap();
+        let token = message.strip_prefix(WS_SESSION_PREFIX).unwrap().to_owned();
+        wait_for(&log, "connect 1");
+
+        // Taking the session over kicks the old connection.
+        let (new, messages) = ws_connect(format!("{}?quad_session={}", url, token));
+        assert!(messages
+            .recv_timeout(timeout)
+            .unwrap()
+            .starts_with(WS_SESSION_PREFIX));
+        wait_for(&log, "suspend 1");
+        wait_for(&log, "resume 1 as 1");
+        loop {
+            match old_messages.recv_timeout(timeout) {
+                Ok(_) => {}
+                Err(err) => {
+                    assert_eq!(err, std::sync::mpsc::RecvTimeoutError::Disconnected);
+                    break;
                 }
             }
-        });
+        }
+
+        new.send("ping").unwrap();
+        assert_eq!(messages.recv_timeout(timeout).unwrap(), "echo:ping");
+        assert_eq!(server.connections(), vec![ConnectionId(1)]);
+
+        server.shutdown();
+    }
+
+    #[test]
+    fn claimed_session_kicks_old_connection() {
+        let (server, log) = start(Some(Duration::from_secs(60)));
+
+        let mut old = Raw::connect(server.tcp_addr(), Some(vec![]));
+        let token = old.session();
+        wait_for(&log, "connect 0");
+
+        let mut new = Raw::connect(server.tcp_addr(), Some(token));
+        new.session();
+        while old.next().is_some() {}
+        wait_for(&log, "resume 0 as 0");
+        assert_eq!(server.connections(), vec![ConnectionId(0)]);
+
+        server.shutdown();
     }
 }
@@ -0,0 +1,114 @@
+//! Book-keeping of every connected client, across both transports.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{tcp::Command, web_socket};
+use crate::{error::Error, quad_socket::protocol};
+
+/// Identifies a connected client, unique for the lifetime of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub(super) u64);
+
+enum Outlet {
+    WebSocket(ws::Sender),
+    /// TCP connections are owned by the event loop, messages for them
+    /// are sent through `Registry::tcp_commands`.
+    Tcp,
+}
+
+pub(super) struct Registry {
+    next_id: AtomicU64,
+    outlets: Mutex<HashMap<ConnectionId, Outlet>>,
+    tcp_commands: Mutex<mio_extras::channel::Sender<Command>>,
+    max_frame_size: usize,
+}
+
+impl Registry {
+    pub fn new(
+        tcp_commands: mio_extras::channel::Sender<Command>,
+        max_frame_size: usize,
+    ) -> Registry {
+        Registry {
+            next_id: AtomicU64::new(0),
+            outlets: Mutex::new(HashMap::new()),
+            tcp_commands: Mutex::new(tcp_commands),
+            max_frame_size,
+        }
+    }
+
+    pub fn next_id(&self) -> ConnectionId {
+        ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn add_web_socket(&self, id: ConnectionId, out: ws::Sender) {
+        self.outlets
+            .lock()
+            .unwrap()
+            .insert(id, Outlet::WebSocket(out));
+    }
+
+    pub fn add_tcp(&self, id: ConnectionId) {
+        self.outlets.lock().unwrap().insert(id, Outlet::Tcp);
+    }
+
+    pub fn remove(&self, id: ConnectionId) {
+        self.outlets.lock().unwrap().remove(&id);
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.outlets.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Make the transport drop the connection as if it was lost,
+    /// used when a session is taken over by a new connection.
+    pub fn kick(&self, id: ConnectionId) {
+        match self.outlets.lock().unwrap().get(&id) {
+            Some(Outlet::WebSocket(out)) => {
+                let _ = out.timeout(0, web_socket::KICK);
+            }
+            Some(Outlet::Tcp) => self.command(Command::Kick(id)),
+            None => {}
+        }
+    }
+
+    pub fn command(&self, command: Command) {
+        let _ = self.tcp_commands.lock().unwrap().send(command);
+    }
+
+    pub fn send_to(&self, id: ConnectionId, data: &[u8]) -> Result<(), Error> {
+        let frame = self.frame(data)?;
+
+        match self.outlets.lock().unwrap().get(&id) {
+            Some(Outlet::WebSocket(out)) => out.send(data)?,
+            Some(Outlet::Tcp) => self.command(Command::Send(id, frame)),
+            None => return Err(Error::ConnectionClosed),
+        }
+
+        Ok(())
+    }
+
+    pub fn broadcast(&self, data: &[u8], except: Option<ConnectionId>) -> Result<(), Error> {
+        let frame = self.frame(data)?;
+
+        for (id, outlet) in self.outlets.lock().unwrap().iter() {
+            if let Outlet::WebSocket(out) = outlet {
+                if Some(*id) != except {
+                    let _ = out.send(data);
+                }
+            }
+        }
+        self.command(Command::Broadcast(frame, except));
+
+        Ok(())
+    }
+
+    /// Frame the data for TCP, also enforcing the frame size limit for WebSockets.
+    fn frame(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut frame = Vec::with_capacity(protocol::HEADER_SIZE + data.len());
+        protocol::write_frame(&mut frame, data, self.max_frame_size)?;
+
+        Ok(frame)
+    }
+}
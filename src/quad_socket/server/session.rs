@@ -0,0 +1,325 @@
+//! Sessions keep the state of a client that lost its connection around
+//! for a while, so it can pick up where it left off after reconnecting.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{ConnectionId, ConnectionInfo, Registry, SharedSettings, SocketHandle};
+use crate::quad_socket::WS_SESSION_QUERY;
+
+/// Secret handed to the client, proves the ownership of a session.
+pub(super) type Token = [u8; 16];
+
+/// What a connecting client asked for.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Request {
+    /// The client would not know what to do with a session.
+    NoSession,
+    /// A new session, or the one with this token.
+    Session(Option<Token>),
+}
+
+pub(super) fn new_token() -> Token {
+    let mut token = [0; 16];
+    getrandom::getrandom(&mut token).expect("No source of randomness for session tokens");
+
+    token
+}
+
+pub(super) fn token_to_hex(token: &Token) -> String {
+    token.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(super) fn token_from_bytes(bytes: &[u8]) -> Option<Token> {
+    let mut token = [0; 16];
+    if bytes.len() != token.len() {
+        return None;
+    }
+    token.copy_from_slice(bytes);
+
+    Some(token)
+}
+
+/// Session asked for in the query string of a WebSocket handshake path,
+/// as `quad_session` for a new one or `quad_session=<token>` to resume one.
+pub(super) fn request_from_path(path: &str) -> Request {
+    let query = match path.split_once('?') {
+        Some((_, query)) => query,
+        None => return Request::NoSession,
+    };
+    let value = query
+        .split('&')
+        .find_map(|param| match param.split_once('=') {
+            Some((name, value)) if name == WS_SESSION_QUERY => Some(value),
+            None if param == WS_SESSION_QUERY => Some(""),
+            _ => None,
+        });
+
+    match value {
+        Some(hex) => Request::Session(token_from_hex(hex)),
+        None => Request::NoSession,
+    }
+}
+
+fn token_from_hex(hex: &str) -> Option<Token> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut token = [0; 16];
+    for (i, byte) in token.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(token)
+}
+
+struct Suspended<S> {
+    id: ConnectionId,
+    state: S,
+    expires: Instant,
+}
+
+struct Inner<S> {
+    /// Tokens of the connected clients.
+    active: HashMap<Token, ConnectionId>,
+    suspended: HashMap<Token, Suspended<S>>,
+}
+
+enum Claim<S> {
+    Resumed(ConnectionId, S),
+    /// The session is still attached to a connection, which might be dead
+    /// without the server knowing yet.
+    Busy(ConnectionId),
+    Unknown,
+}
+
+/// Sessions of both transports.
+pub(super) struct Sessions<S> {
+    grace: Option<Duration>,
+    inner: Mutex<Inner<S>>,
+}
+
+impl<S> Sessions<S> {
+    pub fn new(grace: Option<Duration>) -> Sessions<S> {
+        Sessions {
+            grace,
+            inner: Mutex::new(Inner {
+                active: HashMap::new(),
+                suspended: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.grace.is_some()
+    }
+
+    fn claim(&self, token: &Token) -> Claim<S> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(session) = inner.suspended.remove(token) {
+            return Claim::Resumed(session.id, session.state);
+        }
+        match inner.active.get(token) {
+            Some(id) => Claim::Busy(*id),
+            None => Claim::Unknown,
+        }
+    }
+
+    fn activate(&self, token: Token, id: ConnectionId) {
+        self.inner.lock().unwrap().active.insert(token, id);
+    }
+
+    fn deactivate(&self, token: &Token) {
+        self.inner.lock().unwrap().active.remove(token);
+    }
+
+    fn suspend(&self, token: Token, id: ConnectionId, state: S) {
+        let expires = Instant::now() + self.grace.unwrap_or_default();
+        let mut inner = self.inner.lock().unwrap();
+        inner.active.remove(&token);
+        inner
+            .suspended
+            .insert(token, Suspended { id, state, expires });
+    }
+
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.inner
+            .lock()
+            .unwrap()
+            .suspended
+            .values()
+            .map(|session| session.expires)
+            .min()
+    }
+
+    /// Remove and return the states of the sessions past their grace period.
+    pub fn expired(&self, now: Instant) -> Vec<S> {
+        let suspended = &mut self.inner.lock().unwrap().suspended;
+        let expired: Vec<Token> = suspended
+            .iter()
+            .filter(|(_, session)| session.expires <= now)
+            .map(|(token, _)| *token)
+            .collect();
+
+        expired
+            .iter()
+            .filter_map(|token| suspended.remove(token))
+            .map(|session| session.state)
+            .collect()
+    }
+
+    /// Remove and return the states of all the suspended sessions.
+    pub fn drain(&self) -> Vec<S> {
+        self.inner
+            .lock()
+            .unwrap()
+            .suspended
+            .drain()
+            .map(|(_, session)| session.state)
+            .collect()
+    }
+}
+
+/// A client past `on_connect` or `on_resume`.
+pub(super) struct Established<S> {
+    pub id: ConnectionId,
+    pub state: S,
+    /// Token to send to the client, if it got a session.
+    pub token: Option<Token>,
+    /// The callback asked to disconnect the client right away.
+    pub disconnect: bool,
+}
+
+pub(super) enum Outcome<S> {
+    Established(Established<S>),
+    Rejected,
+    /// The session is being taken over from its previous connection,
+    /// try again a bit later.
+    Pending,
+}
+
+/// Resume the session the client asked for if there is one, otherwise start
+/// a new one with `on_connect`.
+///
+/// `add_outlet` registers the connection under its final id, before any callback runs.
+pub(super) fn establish<S>(
+    registry: &Registry,
+    settings: &SharedSettings<S>,
+    sessions: &Sessions<S>,
+    mut info: ConnectionInfo,
+    request: Request,
+    add_outlet: impl FnOnce(ConnectionId),
+) -> Outcome<S> {
+    let (session, token) = match request {
+        Request::Session(token) if sessions.enabled() => (true, token),
+        _ => (false, None),
+    };
+    let resumed = match token.map(|token| sessions.claim(&token)) {
+        Some(Claim::Resumed(id, state)) => Some((id, state)),
+        Some(Claim::Busy(id)) => {
+            registry.kick(id);
+            return Outcome::Pending;
+        }
+        Some(Claim::Unknown) | None => None,
+    };
+    if let Some((id, _)) = &resumed {
+        info.id = *id;
+    }
+    let id = info.id;
+    add_outlet(id);
+
+    let mut handle = SocketHandle::new(id, registry);
+    let state = match resumed {
+        Some((_, mut state)) => {
+            (settings.lock().unwrap().on_resume)(&mut handle, &mut state, &info);
+            state
+        }
+        None => match (settings.lock().unwrap().on_connect)(&mut handle, &info) {
+            Some(state) => state,
+            None => {
+                registry.remove(id);
+                return Outcome::Rejected;
+            }
+        },
+    };
+
+    // A fresh token every time, an old one is of no use to anybody.
+    let token = if session {
+        let token = new_token();
+        sessions.activate(token, id);
+        Some(token)
+    } else {
+        None
+    };
+
+    Outcome::Established(Established {
+        id,
+        state,
+        token,
+        disconnect: handle.disconnect,
+    })
+}
+
+/// Called once a client's connection is gone.
+///
+/// If the connection was `lost` and the client has a session, the session gets
+/// suspended with `on_suspend`, otherwise the client is gone for good.
+pub(super) fn close<S>(
+    registry: &Registry,
+    settings: &SharedSettings<S>,
+    sessions: &Sessions<S>,
+    id: ConnectionId,
+    mut state: S,
+    token: Option<Token>,
+    lost: bool,
+) {
+    registry.remove(id);
+
+    match token {
+        Some(token) if lost && sessions.enabled() => {
+            let mut handle = SocketHandle::new(id, registry);
+            (settings.lock().unwrap().on_suspend)(&mut handle, &mut state);
+            sessions.suspend(token, id, state);
+            // The TCP loop takes care of the expiration.
+            registry.command(super::tcp::Command::Wake);
+        }
+        _ => {
+            if let Some(token) = token {
+                sessions.deactivate(&token);
+            }
+            (settings.lock().unwrap().on_disconnect)(&state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(path: &str) -> Option<Option<Token>> {
+        match request_from_path(path) {
+            Request::NoSession => None,
+            Request::Session(token) => Some(token),
+        }
+    }
+
+    #[test]
+    fn session_query() {
+        let token = [0xab; 16];
+        let hex = token_to_hex(&token);
+
+        assert_eq!(parse("/"), None);
+        assert_eq!(parse("/?room=1"), None);
+        assert_eq!(parse("/?quad_sessions=1"), None);
+        assert_eq!(parse("/?quad_session"), Some(None));
+        assert_eq!(parse("/?quad_session="), Some(None));
+        assert_eq!(parse("/?quad_session=garbage"), Some(None));
+        assert_eq!(parse(&format!("/?quad_session={}", hex)), Some(Some(token)));
+        assert_eq!(
+            parse(&format!("/game?room=1&quad_session={}", hex)),
+            Some(Some(token))
+        );
+    }
+}
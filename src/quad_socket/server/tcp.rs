@@ -0,0 +1,499 @@
+//! Single threaded, readiness based event loop serving all TCP clients.
+
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::Receiver;
+
+use super::session::{self, Outcome, Request, Sessions};
+use super::{ConnectionId, ConnectionInfo, Registry, SharedSettings, SocketHandle, Transport};
+use crate::{
+    error::Error,
+    quad_socket::protocol::{self, Control, Frame, FrameDecoder},
+};
+
+const LISTENER: Token = Token(0);
+const COMMANDS: Token = Token(1);
+/// Tokens below this value are reserved for the listener and the command channel.
+const FIRST_CONNECTION: usize = 2;
+
+/// How often a connection waiting for its session checks on it.
+const RESUME_RETRY: Duration = Duration::from_millis(10);
+
+/// Reads done for a single connection before the others get their turn.
+const READ_BUDGET: usize = 16;
+
+/// Requests sent to the event loop from other threads.
+pub(super) enum Command {
+    /// Queue an already framed message for a single connection.
+    Send(ConnectionId, Vec<u8>),
+    /// Queue an already framed message for every connection but the excluded one.
+    Broadcast(Vec<u8>, Option<ConnectionId>),
+    /// Drop the connection as if it was lost, see `Registry::kick`.
+    Kick(ConnectionId),
+    /// A session was suspended, its expiration needs to be scheduled.
+    Wake,
+    Shutdown,
+}
+
+struct Connection<S> {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    decoder: FrameDecoder,
+    outbound: Vec<u8>,
+    /// `None` until the client got through `on_connect` or `on_resume`.
+    state: Option<S>,
+    session: Option<session::Token>,
+    /// Waiting for the session to be released by its previous connection.
+    /// Incoming frames are left in the decoder meanwhile.
+    resuming: Option<session::Token>,
+    /// The socket was not read until `WouldBlock` yet.
+    readable: bool,
+    next_tick: Option<Instant>,
+    closed: bool,
+    /// Closed because of the network, rather than on purpose.
+    lost: bool,
+}
+
+impl<S> Connection<S> {
+    /// Whether `Context::receive` has more to read on this connection.
+    fn wants_read(&self) -> bool {
+        self.readable && !self.closed && self.resuming.is_none() && !self.decoder.is_full()
+    }
+
+    /// Performs a single read from the socket.
+    /// Marks the connection as lost on EOF or error.
+    fn read(&mut self) {
+        match self.decoder.read_from(&mut self.stream) {
+            Ok(0) => self.lose(),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => self.readable = false,
+            Err(_) => self.lose(),
+        }
+    }
+
+    /// Writes as much of the outbound buffer as the socket accepts.
+    fn flush(&mut self) {
+        while !self.outbound.is_empty() {
+            match self.stream.write(&self.outbound) {
+                Ok(0) => {
+                    self.lose();
+                    return;
+                }
+                Ok(n) => {
+                    self.outbound.drain(0..n);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.lose();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn lose(&mut self) {
+        if !self.closed {
+            self.lost = true;
+        }
+        self.closed = true;
+    }
+}
+
+/// Everything the connections need from the server.
+pub(super) struct Context<S> {
+    pub registry: Arc<Registry>,
+    pub settings: SharedSettings<S>,
+    pub sessions: Arc<Sessions<S>>,
+    pub timer: Option<Duration>,
+    pub max_frame_size: usize,
+}
+
+impl<S> Context<S> {
+    /// Get the client through `on_connect` or `on_resume`.
+    /// Returns the id the connection is known under from now on.
+    fn establish(
+        &self,
+        id: ConnectionId,
+        connection: &mut Connection<S>,
+        request: Request,
+    ) -> ConnectionId {
+        let info = ConnectionInfo {
+            id,
+            peer_addr: Some(connection.peer_addr),
+            transport: Transport::Tcp,
+            path: None,
+            headers: vec![],
+            origin: None,
+        };
+        let established = match session::establish(
+            &self.registry,
+            &self.settings,
+            &self.sessions,
+            info,
+            request,
+            |id| self.registry.add_tcp(id),
+        ) {
+            Outcome::Established(established) => established,
+            Outcome::Rejected => {
+                connection.closed = true;
+                return id;
+            }
+            Outcome::Pending => {
+                if let Request::Session(token) = request {
+                    connection.resuming = token;
+                }
+                return id;
+            }
+        };
+
+        connection.resuming = None;
+        connection.state = Some(established.state);
+        connection.session = established.token;
+        connection.next_tick = self.timer.map(|timer| Instant::now() + timer);
+        connection.closed |= established.disconnect;
+        if let Some(token) = established.token {
+            let _ = protocol::write_control(
+                &mut connection.outbound,
+                &Control::Session(token.to_vec()),
+            );
+        }
+
+        established.id
+    }
+
+    /// Alternates reads and `process_frames`, so the decoder never holds more
+    /// than a frame, up to `READ_BUDGET` reads.
+    /// Returns the id the connection is known under from now on.
+    fn receive(&self, id: ConnectionId, connection: &mut Connection<S>) -> ConnectionId {
+        let mut id = self.process_frames(id, connection);
+        for _ in 0..READ_BUDGET {
+            if !connection.wants_read() {
+                break;
+            }
+            connection.read();
+            id = self.process_frames(id, connection);
+        }
+        id
+    }
+
+    /// Handle every complete frame received so far.
+    /// Returns the id the connection is known under from now on.
+    fn process_frames(&self, mut id: ConnectionId, connection: &mut Connection<S>) -> ConnectionId {
+        // Frames that came in before a lost connection are still delivered.
+        while (connection.lost || !connection.closed) && connection.resuming.is_none() {
+            let frame = match connection.decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => {
+                    connection.closed = true;
+                    break;
+                }
+            };
+
+            match frame {
+                Frame::Control(Control::Hello(token)) => {
+                    if connection.state.is_none() {
+                        let request = match token {
+                            Some(token) => Request::Session(session::token_from_bytes(&token)),
+                            None => Request::NoSession,
+                        };
+                        id = self.establish(id, connection, request);
+                    }
+                }
+                Frame::Control(Control::Goodbye) => {
+                    // Often read together with the EOF that follows it.
+                    connection.closed = true;
+                    connection.lost = false;
+                }
+                Frame::Control(_) => {}
+                Frame::Message(message) => {
+                    // A client skipping the hello, it does not care about sessions.
+                    if connection.state.is_none() {
+                        id = self.establish(id, connection, Request::NoSession);
+                    }
+                    if let Some(state) = &mut connection.state {
+                        let mut handle = SocketHandle::new(id, &self.registry);
+                        (self.settings.lock().unwrap().on_message)(&mut handle, state, message);
+                        connection.closed |= handle.disconnect;
+                    }
+                }
+            }
+        }
+
+        id
+    }
+
+    fn close(&self, id: ConnectionId, connection: Connection<S>, lost: bool) {
+        if let Some(state) = connection.state {
+            session::close(
+                &self.registry,
+                &self.settings,
+                &self.sessions,
+                id,
+                state,
+                connection.session,
+                lost,
+            );
+        }
+    }
+}
+
+pub(super) fn run<S>(
+    listener: std::net::TcpListener,
+    commands: Receiver<Command>,
+    context: Context<S>,
+) -> Result<(), Error> {
+    let Context {
+        registry,
+        settings,
+        sessions,
+        ..
+    } = &context;
+    let (timer, max_frame_size) = (context.timer, context.max_frame_size);
+    let listener = TcpListener::from_std(listener)?;
+    let poll = Poll::new()?;
+    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge())?;
+    poll.register(&commands, COMMANDS, Ready::readable(), PollOpt::edge())?;
+
+    let mut connections: HashMap<ConnectionId, Connection<S>> = HashMap::new();
+    let mut events = Events::with_capacity(1024);
+
+    // Moves a connection to the id of its resumed session.
+    let rekey = |connections: &mut HashMap<ConnectionId, Connection<S>>,
+                 old: ConnectionId,
+                 new: ConnectionId| {
+        if old == new {
+            return;
+        }
+        let mut connection = connections.remove(&old).unwrap();
+        if poll
+            .reregister(
+                &connection.stream,
+                Token(new.0 as usize + FIRST_CONNECTION),
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            )
+            .is_err()
+        {
+            connection.lose();
+        }
+        connections.insert(new, connection);
+    };
+
+    loop {
+        let now = Instant::now();
+        let mut timeout = connections
+            .values()
+            .filter_map(|connection| connection.next_tick)
+            .chain(sessions.next_expiry())
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now));
+        if connections
+            .values()
+            .any(|connection| connection.resuming.is_some())
+        {
+            timeout = Some(timeout.map_or(RESUME_RETRY, |timeout| timeout.min(RESUME_RETRY)));
+        }
+        if connections.values().any(Connection::wants_read) {
+            // Out of budget, the edge triggered poll would not report them again.
+            timeout = Some(Duration::from_secs(0));
+        }
+
+        if let Err(err) = poll.poll(&mut events, timeout) {
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+
+        for event in &events {
+            if event.token() == COMMANDS {
+                continue;
+            }
+
+            if event.token() == LISTENER {
+                loop {
+                    let (stream, peer_addr) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                        Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                        Err(err) => return Err(err.into()),
+                    };
+                    let _ = stream.set_nodelay(true);
+
+                    let id = registry.next_id();
+                    if poll
+                        .register(
+                            &stream,
+                            Token(id.0 as usize + FIRST_CONNECTION),
+                            Ready::readable() | Ready::writable(),
+                            PollOpt::edge(),
+                        )
+                        .is_err()
+                    {
+                        continue;
+                    }
+
+                    let mut connection = Connection {
+                        stream,
+                        peer_addr,
+                        decoder: FrameDecoder::new(max_frame_size),
+                        outbound: vec![],
+                        state: None,
+                        session: None,
+                        resuming: None,
+                        readable: false,
+                        next_tick: None,
+                        closed: false,
+                        lost: false,
+                    };
+                    if sessions.enabled() {
+                        // The client has to say whether it resumes a session
+                        // with its hello before anything else can happen.
+                        connections.insert(id, connection);
+                    } else {
+                        let id = context.establish(id, &mut connection, Request::NoSession);
+                        connection.flush();
+                        connections.insert(id, connection);
+                    }
+                }
+                continue;
+            }
+
+            let id = ConnectionId((event.token().0 - FIRST_CONNECTION) as u64);
+            let connection = match connections.get_mut(&id) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            if event.readiness().is_readable() {
+                connection.readable = true;
+            }
+            let new_id = context.receive(id, connection);
+            connection.flush();
+            rekey(&mut connections, id, new_id);
+        }
+
+        let resuming: Vec<ConnectionId> = connections
+            .iter()
+            .filter(|(_, connection)| connection.resuming.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in resuming {
+            let connection = connections.get_mut(&id).unwrap();
+            let request = Request::Session(connection.resuming.take());
+            let mut new_id = context.establish(id, connection, request);
+            if connection.resuming.is_none() {
+                new_id = context.receive(new_id, connection);
+            }
+            connection.flush();
+            rekey(&mut connections, id, new_id);
+        }
+
+        // Connections that ran out of budget, or were held up by a full decoder.
+        let pending: Vec<ConnectionId> = connections
+            .iter()
+            .filter(|(_, connection)| connection.wants_read())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in pending {
+            let connection = connections.get_mut(&id).unwrap();
+            let new_id = context.receive(id, connection);
+            connection.flush();
+            rekey(&mut connections, id, new_id);
+        }
+
+        if let Some(timer) = timer {
+            let now = Instant::now();
+            for (id, connection) in connections.iter_mut() {
+                let state = match (&connection.state, connection.next_tick) {
+                    (Some(state), Some(next_tick)) if next_tick <= now && !connection.closed => {
+                        state
+                    }
+                    _ => continue,
+                };
+                connection.next_tick = Some(now + timer);
+
+                let mut handle = SocketHandle::new(*id, registry);
+                (settings.lock().unwrap().on_timer)(&mut handle, state);
+                if handle.disconnect {
+                    connection.closed = true;
+                }
+            }
+        }
+
+        for state in sessions.expired(Instant::now()) {
+            (settings.lock().unwrap().on_disconnect)(&state);
+        }
+
+        // Done after all the callbacks, so messages queued right before
+        // a disconnect still make it into the outbound buffers.
+        if drain_commands(&commands, &mut connections) {
+            for (id, mut connection) in connections.drain() {
+                connection.flush();
+                let _ = poll.deregister(&connection.stream);
+                context.close(id, connection, false);
+            }
+            for state in sessions.drain() {
+                (settings.lock().unwrap().on_disconnect)(&state);
+            }
+            return Ok(());
+        }
+
+        let closed: Vec<ConnectionId> = connections
+            .iter()
+            .filter(|(_, connection)| connection.closed)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in closed {
+            let mut connection = connections.remove(&id).unwrap();
+            connection.flush();
+            let _ = poll.deregister(&connection.stream);
+            let lost = connection.lost;
+            context.close(id, connection, lost);
+        }
+    }
+}
+
+/// Apply all pending commands, returns true if the server should shut down.
+fn drain_commands<S>(
+    commands: &Receiver<Command>,
+    connections: &mut HashMap<ConnectionId, Connection<S>>,
+) -> bool {
+    let mut shutdown = false;
+
+    while let Ok(command) = commands.try_recv() {
+        match command {
+            Command::Send(id, frame) => {
+                if let Some(connection) = connections.get_mut(&id) {
+                    connection.outbound.extend_from_slice(&frame);
+                    connection.flush();
+                }
+            }
+            Command::Broadcast(frame, except) => {
+                for (id, connection) in connections.iter_mut() {
+                    if Some(*id) != except && connection.state.is_some() {
+                        connection.outbound.extend_from_slice(&frame);
+                        connection.flush();
+                    }
+                }
+            }
+            Command::Kick(id) => {
+                if let Some(connection) = connections.get_mut(&id) {
+                    connection.lose();
+                }
+            }
+            Command::Wake => {}
+            Command::Shutdown => shutdown = true,
+        }
+    }
+
+    shutdown
+}
@@ -0,0 +1,208 @@
+//! WebSocket side of the server, driven by ws-rs.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::session::{self, Outcome, Request, Sessions, Token};
+use super::{ConnectionId, ConnectionInfo, Registry, SharedSettings, SocketHandle, Transport};
+use crate::{error::Error, quad_socket::WS_SESSION_PREFIX};
+
+const TIMER: ws::util::Token = ws::util::Token(1);
+/// Retry resuming a session, see `Outcome::Pending`.
+const RESUME: ws::util::Token = ws::util::Token(2);
+/// Scheduled by `Registry::kick` from any thread.
+pub(super) const KICK: ws::util::Token = ws::util::Token(3);
+
+/// How often a connection waiting for its session checks on it.
+const RESUME_RETRY_MILLIS: u64 = 10;
+
+struct WsHandler<S> {
+    out: ws::Sender,
+    id: ConnectionId,
+    registry: Arc<Registry>,
+    /// Created by `on_connect` once the handshake is done,
+    /// `None` before that and after the disconnect.
+    state: Option<S>,
+    settings: SharedSettings<S>,
+    sessions: Arc<Sessions<S>>,
+    session: Option<Token>,
+    /// Waiting for the session to be released by its previous connection.
+    resuming: Option<(ConnectionInfo, Token)>,
+    /// Messages received while `resuming`.
+    pending: Vec<Vec<u8>>,
+    timeout: Option<Duration>,
+}
+
+// Helpers of the `ws::Handler` impl, returning its error type.
+#[allow(clippy::result_large_err)]
+impl<S> WsHandler<S> {
+    fn establish(&mut self, info: ConnectionInfo, request: Request) -> ws::Result<()> {
+        let out = self.out.clone();
+        let established = match session::establish(
+            &self.registry,
+            &self.settings,
+            &self.sessions,
+            info.clone(),
+            request,
+            |id| self.registry.add_web_socket(id, out),
+        ) {
+            Outcome::Established(established) => established,
+            Outcome::Rejected => return self.out.close(ws::CloseCode::Policy),
+            Outcome::Pending => {
+                if let Request::Session(token) = request {
+                    self.resuming = token.map(|token| (info, token));
+                }
+                return self.out.timeout(RESUME_RETRY_MILLIS, RESUME);
+            }
+        };
+
+        self.id = established.id;
+        self.state = Some(established.state);
+        self.session = established.token;
+        if let Some(token) = &self.session {
+            self.out.send(format!(
+                "{}{}",
+                WS_SESSION_PREFIX,
+                session::token_to_hex(token)
+            ))?;
+        }
+        if established.disconnect {
+            return self.out.close(ws::CloseCode::Normal);
+        }
+
+        for message in std::mem::take(&mut self.pending) {
+            self.message(message)?;
+        }
+
+        if let Some(timeout) = self.timeout {
+            self.out.timeout(timeout.as_millis() as _, TIMER)?;
+        }
+        Ok(())
+    }
+
+    fn message(&mut self, data: Vec<u8>) -> ws::Result<()> {
+        let state = match &mut self.state {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        let mut handle = SocketHandle::new(self.id, &self.registry);
+        (self.settings.lock().unwrap().on_message)(&mut handle, state, data);
+        if handle.disconnect {
+            self.out.close(ws::CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, lost: bool) {
+        if let Some(state) = self.state.take() {
+            session::close(
+                &self.registry,
+                &self.settings,
+                &self.sessions,
+                self.id,
+                state,
+                self.session,
+                lost,
+            );
+        }
+    }
+}
+
+impl<S: Send + 'static> ws::Handler for WsHandler<S> {
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        if self.resuming.is_some() {
+            self.pending.push(msg.into_data());
+            return Ok(());
+        }
+        self.message(msg.into_data())
+    }
+
+    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+        let request = &shake.request;
+        let info = ConnectionInfo {
+            id: self.id,
+            peer_addr: shake.peer_addr,
+            transport: Transport::WebSocket,
+            path: Some(request.resource().to_owned()),
+            headers: request
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.clone(), String::from_utf8_lossy(value).into_owned()))
+                .collect(),
+            origin: request
+                .origin()
+                .ok()
+                .flatten()
+                .map(|origin| origin.to_owned()),
+        };
+        let request = session::request_from_path(request.resource());
+
+        self.establish(info, request)
+    }
+
+    fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+        match event {
+            TIMER => {
+                if let (Some(timeout), Some(state)) = (self.timeout, &self.state) {
+                    let mut handle = SocketHandle::new(self.id, &self.registry);
+                    (self.settings.lock().unwrap().on_timer)(&mut handle, state);
+                    if !handle.disconnect {
+                        self.out.timeout(timeout.as_millis() as _, TIMER)?;
+                    } else {
+                        self.out.close(ws::CloseCode::Normal)?;
+                    }
+                }
+            }
+            RESUME => {
+                if let Some((info, token)) = self.resuming.take() {
+                    self.establish(info, Request::Session(Some(token)))?;
+                }
+            }
+            KICK => {
+                self.close(true);
+                self.out.close(ws::CloseCode::Away)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, _reason: &str) {
+        // Anything but a proper goodbye might be a network hiccup.
+        let lost = !matches!(code, ws::CloseCode::Normal | ws::CloseCode::Away);
+        self.close(lost);
+    }
+
+    fn on_shutdown(&mut self) {
+        self.close(false);
+    }
+}
+
+/// Build a ws-rs server, bind it and call `run` to serve it.
+pub(super) fn build<S: Send + 'static>(
+    registry: Arc<Registry>,
+    settings: SharedSettings<S>,
+    sessions: Arc<Sessions<S>>,
+    timer: Option<Duration>,
+) -> Result<ws::WebSocket<impl ws::Factory<Handler = impl ws::Handler + Send> + Send>, Error> {
+    let ws = ws::Builder::new()
+        .with_settings(ws::Settings {
+            timer_tick_millis: 10,
+            tcp_nodelay: true,
+            ..ws::Settings::default()
+        })
+        .build(move |out| WsHandler {
+            out,
+            id: registry.next_id(),
+            registry: registry.clone(),
+            state: None,
+            settings: settings.clone(),
+            sessions: sessions.clone(),
+            session: None,
+            resuming: None,
+            pending: vec![],
+            timeout: timer,
+        })?;
+
+    Ok(ws)
+}
@@ -1,151 +1,489 @@
 //! Websocket client. Works through native websockets on web and through ws-rs on the desktop.
 
+use std::collections::VecDeque;
+
+use crate::error::Error;
+
+/// A received message, in the kind of frame it was sent as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Message {
+    /// The text as UTF-8 bytes or the binary data.
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) => data,
+        }
+    }
+}
+
+/// Everything that happens to a `WebSocket`, in order, see `WebSocket::poll_event`.
+#[derive(Debug, Clone)]
+pub enum Event {
+    Open,
+    Message(Message),
+    /// Closed by either side. 1000 is a normal closure, other codes
+    /// usually come from the server, like 1008 for a policy violation.
+    Close {
+        code: u16,
+        reason: String,
+    },
+    /// The connection could not be established or broke without
+    /// a closing handshake. Nothing follows an error.
+    Error(Error),
+}
+
+/// Options of the handshake, `WebSocket::connect` goes with the defaults.
+#[derive(Debug, Clone)]
+pub struct WebSocketBuilder {
+    addr: String,
+    protocols: Vec<String>,
+    /// Only sent on the desktop.
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    headers: Vec<(String, String)>,
+}
+
+impl WebSocketBuilder {
+    pub fn new(addr: &str) -> WebSocketBuilder {
+        WebSocketBuilder {
+            addr: addr.to_owned(),
+            protocols: vec![],
+            headers: vec![],
+        }
+    }
+
+    /// Offer a subprotocol, in the order of preference. The one the server
+    /// picked is then given by `WebSocket::protocol`.
+    pub fn protocol(mut self, protocol: &str) -> WebSocketBuilder {
+        self.protocols.push(protocol.to_owned());
+        self
+    }
+
+    /// Extra handshake header, like `Authorization` or `Cookie`.
+    /// Browsers don't allow any, so this does nothing on the web.
+    pub fn header(mut self, header: &str, value: &str) -> WebSocketBuilder {
+        self.headers.push((header.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Returns right away, in the `Connecting` state. The outcome is an
+    /// `Event::Open` or an `Event::Error`.
+    pub fn connect(self) -> Result<WebSocket, Error> {
+        WebSocket::connect_with(self)
+    }
+}
+
+/// Take the first message out of the events, leaving the rest in place.
+fn take_message(events: &mut VecDeque<Event>) -> Option<Message> {
+    let index = events
+        .iter()
+        .position(|event| matches!(event, Event::Message(_)))?;
+
+    match events.remove(index) {
+        Some(Event::Message(message)) => Some(message),
+        _ => None,
+    }
+}
+
 #[cfg(target_arch = "wasm32")]
 pub(crate) mod js_web_socket {
+    use std::collections::VecDeque;
     use std::net::ToSocketAddrs;
 
     use sapp_jsutils::JsObject;
 
+    use super::{Event, Message, WebSocketBuilder};
     use crate::error::Error;
+    use crate::quad_socket::client::ConnectionState;
 
-    pub struct WebSocket;
+    pub struct WebSocket {
+        /// Id of the socket in quad-net.js, like the `cid` of http requests.
+        id: i32,
+        events: VecDeque<Event>,
+    }
 
     extern "C" {
-        fn ws_connect(addr: JsObject);
-        fn ws_send(buffer: JsObject);
-        fn ws_try_recv() -> JsObject;
-        fn ws_is_connected() -> i32;
+        fn ws_connect(addr: JsObject, protocols: JsObject) -> i32;
+        fn ws_protocol(id: i32) -> JsObject;
+        fn ws_send(id: i32, buffer: JsObject);
+        fn ws_close(id: i32, code: u32, reason: JsObject);
+        fn ws_forget(id: i32);
+        fn ws_state(id: i32) -> i32;
+        fn ws_close_reason(id: i32) -> JsObject;
+        fn ws_poll_event(id: i32) -> JsObject;
+    }
+
+    /// Browsers do not expose any details on WebSocket errors.
+    fn web_socket_error() -> Error {
+        std::io::Error::other("WebSocket error").into()
     }
 
     impl WebSocket {
-        pub fn send_text(&self, text: &str) {
-            unsafe { ws_send(JsObject::string(text)) };
+        pub fn send_text(&self, text: &str) -> Result<(), Error> {
+            if !self.connected() {
+                return Err(Error::ConnectionClosed);
+            }
+            unsafe { ws_send(self.id, JsObject::string(text)) };
+
+            Ok(())
         }
 
-        pub fn send_bytes(&self, data: &[u8]) {
-            unsafe { ws_send(JsObject::buffer(data)) };
+        pub fn send_bytes(&self, data: &[u8]) -> Result<(), Error> {
+            if !self.connected() {
+                return Err(Error::ConnectionClosed);
+            }
+            unsafe { ws_send(self.id, JsObject::buffer(data)) };
+
+            Ok(())
         }
 
-        pub fn try_recv(&mut self) -> Option<Vec<u8>> {
-            let data = unsafe { ws_try_recv() };
-            if data.is_nil() == false {
-                let is_text = data.field_u32("text") == 1;
-                let mut buf = vec![];
-                if is_text {
-                    let mut s = String::new();
-                    data.field("data").to_string(&mut s);
-                    buf = s.into_bytes();
-                } else {
-                    data.field("data").to_byte_buffer(&mut buf);
+        /// Start the closing handshake. Browsers only allow 1000 and
+        /// 3000-4999 as `code`, and up to 123 bytes of `reason`.
+        pub fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+            if !(code == 1000 || (3000..5000).contains(&code)) || reason.len() > 123 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Close code or reason not allowed by browsers",
+                )
+                .into());
+            }
+            unsafe { ws_close(self.id, code as u32, JsObject::string(reason)) };
+
+            Ok(())
+        }
+
+        /// Next message, the other events stay for `poll_event`.
+        pub fn try_recv(&mut self) -> Option<Message> {
+            self.pump();
+
+            super::take_message(&mut self.events)
+        }
+
+        pub fn poll_event(&mut self) -> Option<Event> {
+            self.pump();
+
+            self.events.pop_front()
+        }
+
+        /// Next event other than a message, the messages stay for `try_recv`.
+        pub(crate) fn poll_connection_event(&mut self) -> Option<Event> {
+            self.pump();
+
+            let index = self
+                .events
+                .iter()
+                .position(|event| !matches!(event, Event::Message(_)))?;
+            self.events.remove(index)
+        }
+
+        fn pump(&mut self) {
+            loop {
+                let event = unsafe { ws_poll_event(self.id) };
+                if event.is_nil() {
+                    return;
                 }
-                return Some(buf);
+
+                // Kept in sync with `ws_poll_event` in quad-net.js
+                self.events.push_back(match event.field_u32("kind") {
+                    0 => Event::Open,
+                    1 => {
+                        let mut reason = String::new();
+                        event.field("reason").to_string(&mut reason);
+                        Event::Close {
+                            code: event.field_u32("code") as u16,
+                            reason,
+                        }
+                    }
+                    3 if event.field_u32("text") == 1 => {
+                        let mut text = String::new();
+                        event.field("data").to_string(&mut text);
+                        Event::Message(Message::Text(text))
+                    }
+                    3 => {
+                        let mut buf = vec![];
+                        event.field("data").to_byte_buffer(&mut buf);
+                        Event::Message(Message::Binary(buf))
+                    }
+                    _ => Event::Error(web_socket_error()),
+                });
             }
-            None
         }
 
         pub fn connected(&self) -> bool {
-            unsafe { ws_is_connected() == 1 }
+            unsafe { ws_state(self.id) == 1 }
+        }
+
+        pub fn state(&self) -> ConnectionState {
+            match unsafe { ws_state(self.id) } {
+                0 => ConnectionState::Connecting,
+                1 => ConnectionState::Connected,
+                2 => {
+                    let mut reason = String::new();
+                    unsafe { ws_close_reason(self.id) }.to_string(&mut reason);
+                    ConnectionState::Closed { reason }
+                }
+                _ => ConnectionState::Failed(web_socket_error()),
+            }
+        }
+
+        /// The subprotocol picked by the server, once connected.
+        pub fn protocol(&self) -> Option<String> {
+            let mut protocol = String::new();
+            unsafe { ws_protocol(self.id) }.to_string(&mut protocol);
+
+            Some(protocol).filter(|protocol| !protocol.is_empty())
         }
 
         pub fn connect<A: ToSocketAddrs + std::fmt::Display>(addr: A) -> Result<WebSocket, Error> {
-            unsafe { ws_connect(JsObject::string(&format!("{}", addr))) };
+            WebSocketBuilder::new(&format!("{}", addr)).connect()
+        }
+
+        pub(super) fn connect_with(builder: WebSocketBuilder) -> Result<WebSocket, Error> {
+            let id = unsafe {
+                ws_connect(
+                    JsObject::string(&builder.addr),
+                    JsObject::string(&builder.protocols.join(",")),
+                )
+            };
 
-            Ok(WebSocket)
+            Ok(WebSocket {
+                id,
+                events: VecDeque::new(),
+            })
+        }
+    }
+
+    impl Drop for WebSocket {
+        fn drop(&mut self) {
+            unsafe { ws_forget(self.id) };
         }
     }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 mod pc_web_socket {
+    use std::collections::VecDeque;
     use std::net::ToSocketAddrs;
-    use std::sync::{mpsc, Mutex};
+    use std::sync::{mpsc, Arc, Mutex};
 
+    use super::{Event, Message, WebSocketBuilder};
     use crate::error::Error;
+    use crate::quad_socket::client::ConnectionState;
 
-    pub struct WebSocket {
-        sender: ws::Sender,
-        rx: Mutex<mpsc::Receiver<Event>>,
+    /// Between the `WebSocket` and its connection thread.
+    struct Shared {
+        /// Set once the connection is made, before the handshake.
+        sender: Mutex<Option<ws::Sender>>,
+        state: Mutex<ConnectionState>,
+        /// `close` called before the handshake was done.
+        close: Mutex<Option<(u16, String)>>,
+        /// Picked by the server in the handshake.
+        protocol: Mutex<Option<String>>,
     }
 
-    enum Event {
-        Connect(ws::Sender),
-        Message(Vec<u8>),
+    pub struct WebSocket {
+        shared: Arc<Shared>,
+        rx: Mutex<mpsc::Receiver<Event>>,
+        events: VecDeque<Event>,
     }
 
     struct Client {
         out: ws::Sender,
         thread_out: mpsc::Sender<Event>,
+        shared: Arc<Shared>,
+        builder: Arc<WebSocketBuilder>,
+        failed: bool,
     }
 
     impl ws::Handler for Client {
-        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
-            self.thread_out
-                .send(Event::Connect(self.out.clone()))
-                .unwrap();
+        fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
+            let mut request = ws::Request::from_url(url)?;
+            for protocol in &self.builder.protocols {
+                request.add_protocol(protocol);
+            }
+            for (header, value) in &self.builder.headers {
+                request
+                    .headers_mut()
+                    .push((header.clone(), value.clone().into_bytes()));
+            }
+
+            Ok(request)
+        }
+
+        fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+            *self.shared.protocol.lock().unwrap() = shake
+                .response
+                .protocol()?
+                .map(|protocol| protocol.to_owned());
+            *self.shared.state.lock().unwrap() = ConnectionState::Connected;
+            let _ = self.thread_out.send(Event::Open);
+
+            if let Some((code, reason)) = self.shared.close.lock().unwrap().take() {
+                self.out
+                    .close_with_reason(ws::CloseCode::from(code), reason)?;
+            }
             Ok(())
         }
 
         fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
-            self.thread_out
-                .send(Event::Message(msg.into_data()))
-                .unwrap();
+            let msg = match msg {
+                ws::Message::Text(text) => Message::Text(text),
+                ws::Message::Binary(data) => Message::Binary(data),
+            };
+            let _ = self.thread_out.send(Event::Message(msg));
             Ok(())
         }
 
-        fn on_close(&mut self, code: ws::CloseCode, _reason: &str) {
-            println!("closed {:?}", code);
+        fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+            // Same as in browsers, nothing follows an error.
+            if self.failed {
+                return;
+            }
+            let code: u16 = code.into();
+            *self.shared.state.lock().unwrap() = ConnectionState::Closed {
+                reason: if reason.is_empty() {
+                    format!("Closed with code {}", code)
+                } else {
+                    reason.to_owned()
+                },
+            };
+            let _ = self.thread_out.send(Event::Close {
+                code,
+                reason: reason.to_owned(),
+            });
         }
 
         fn on_error(&mut self, error: ws::Error) {
-            println!("{:?}", error);
+            if !self.failed {
+                self.failed = true;
+                let error = Error::from(error);
+                *self.shared.state.lock().unwrap() = ConnectionState::Failed(error.clone());
+                let _ = self.thread_out.send(Event::Error(error));
+            }
         }
     }
 
     impl WebSocket {
+        /// Returns right away, in the `Connecting` state. The outcome is an
+        /// `Event::Open` or an `Event::Error`.
         pub fn connect<A: ToSocketAddrs + std::fmt::Display>(addr: A) -> Result<WebSocket, Error> {
+            WebSocketBuilder::new(&format!("{}", addr)).connect()
+        }
+
+        pub(super) fn connect_with(builder: WebSocketBuilder) -> Result<WebSocket, Error> {
             let (tx, rx) = mpsc::channel();
-            let ws_addr = format!("{}", addr);
-            std::thread::spawn(move || {
-                ws::connect(ws_addr, |out| Client {
-                    out,
-                    thread_out: tx.clone(),
-                })
-                .unwrap()
+            let shared = Arc::new(Shared {
+                sender: Mutex::new(None),
+                state: Mutex::new(ConnectionState::Connecting),
+                close: Mutex::new(None),
+                protocol: Mutex::new(None),
+            });
+            let ws_addr = builder.addr.clone();
+            let builder = Arc::new(builder);
+            std::thread::spawn({
+                let shared = shared.clone();
+                move || {
+                    let result = ws::connect(ws_addr, |out| {
+                        *shared.sender.lock().unwrap() = Some(out.clone());
+                        Client {
+                            out,
+                            thread_out: tx.clone(),
+                            shared: shared.clone(),
+                            builder: builder.clone(),
+                            failed: false,
+                        }
+                    });
+                    if let Err(err) = result {
+                        let error = Error::from(err);
+                        let mut state = shared.state.lock().unwrap();
+                        if !matches!(*state, ConnectionState::Failed(_)) {
+                            *state = ConnectionState::Failed(error.clone());
+                            let _ = tx.send(Event::Error(error));
+                        }
+                    }
+                }
             });
 
-            match rx.recv() {
-                Ok(Event::Connect(sender)) => Ok(WebSocket {
-                    sender,
-                    rx: Mutex::new(rx),
-                }),
-                _ => panic!("Failed to connect websocket"),
-            }
+            Ok(WebSocket {
+                shared,
+                rx: Mutex::new(rx),
+                events: VecDeque::new(),
+            })
         }
 
         pub fn connected(&self) -> bool {
-            true
+            matches!(self.state(), ConnectionState::Connected)
+        }
+
+        pub fn state(&self) -> ConnectionState {
+            self.shared.state.lock().unwrap().clone()
+        }
+
+        /// The subprotocol picked by the server, once connected.
+        pub fn protocol(&self) -> Option<String> {
+            self.shared.protocol.lock().unwrap().clone()
+        }
+
+        /// Next message, the other events stay for `poll_event`.
+        pub fn try_recv(&mut self) -> Option<Message> {
+            self.pump();
+
+            super::take_message(&mut self.events)
+        }
+
+        pub fn poll_event(&mut self) -> Option<Event> {
+            self.pump();
+
+            self.events.pop_front()
         }
 
-        pub fn try_recv(&mut self) -> Option<Vec<u8>> {
-            self.rx
-                .lock()
-                .unwrap()
-                .try_recv()
-                .ok()
-                .map(|event| match event {
-                    Event::Message(msg) => msg,
-                    _ => panic!(),
-                })
+        fn pump(&mut self) {
+            let rx = self.rx.lock().unwrap();
+            self.events.extend(rx.try_iter());
         }
 
-        pub fn send_text(&self, text: &str) {
-            self.sender.send(ws::Message::text(text)).unwrap();
+        fn send(&self, message: ws::Message) -> Result<(), Error> {
+            if !self.connected() {
+                return Err(Error::ConnectionClosed);
+            }
+            match &*self.shared.sender.lock().unwrap() {
+                Some(sender) => sender.send(message)?,
+                None => return Err(Error::ConnectionClosed),
+            }
+
+            Ok(())
         }
 
-        pub fn send_bytes(&self, data: &[u8]) {
-            self.sender
-                .send(ws::Message::Binary(data.to_vec()))
-                .unwrap();
+        pub fn send_text(&self, text: &str) -> Result<(), Error> {
+            self.send(ws::Message::text(text))
+        }
+
+        pub fn send_bytes(&self, data: &[u8]) -> Result<(), Error> {
+            self.send(ws::Message::Binary(data.to_vec()))
+        }
+
+        /// Start the closing handshake, an `Event::Close` follows once it is done.
+        /// While still connecting, the connection is closed as soon as it opens.
+        pub fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+            // Under the state lock, not to race with `on_open`.
+            let state = self.shared.state.lock().unwrap();
+            match &*state {
+                ConnectionState::Connecting => {
+                    *self.shared.close.lock().unwrap() = Some((code, reason.to_owned()));
+                }
+                ConnectionState::Connected => {
+                    if let Some(sender) = &*self.shared.sender.lock().unwrap() {
+                        sender.close_with_reason(ws::CloseCode::from(code), reason.to_owned())?;
+                    }
+                }
+                _ => return Err(Error::ConnectionClosed),
+            }
+
+            Ok(())
         }
     }
 }